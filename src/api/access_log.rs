@@ -0,0 +1,57 @@
+use std::time::Instant;
+
+use axum::body::HttpBody;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use reqwest::header::HOST;
+use serde_json::json;
+use tracing::info;
+
+use crate::config::AccessLogFormat;
+use crate::AppState;
+
+use super::X_CACHE;
+
+// log one line per request with its response, in the format of the configuration.
+// the cache result is the X-Cache header of the response, "-" if the cache was not used.
+pub async fn access_log(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let format = state.config.read().await.access_log;
+    if format == AccessLogFormat::Off {
+        return next.run(request).await;
+    }
+    let start = Instant::now();
+    let method = request.method().clone();
+    let host = header_str(request.headers().get(HOST)).to_string();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or_else(|| "/".to_string(), |p| p.to_string());
+    let rep = next.run(request).await;
+    let latency_ms = start.elapsed().as_millis();
+    let status = rep.status().as_u16();
+    let size = rep.body().size_hint().exact();
+    let cache = header_str(rep.headers().get(X_CACHE));
+    let line = match format {
+        AccessLogFormat::Json => json!({
+            "method": method.as_str(),
+            "host": host,
+            "path": path,
+            "status": status,
+            "size": size,
+            "cache": cache,
+            "latency_ms": latency_ms,
+        })
+        .to_string(),
+        _ => format!(
+            "{method} {host} {path} {status} {} {cache} {latency_ms}ms",
+            size.map_or_else(|| "-".to_string(), |s| s.to_string())
+        ),
+    };
+    info!(target: "mnemosyne::access", "{line}");
+    rep
+}
+
+fn header_str(value: Option<&axum::http::HeaderValue>) -> &str {
+    value.and_then(|v| v.to_str().ok()).unwrap_or("-")
+}
@@ -0,0 +1,33 @@
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use reqwest::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+use subtle::ConstantTimeEq;
+use tracing::debug;
+
+use crate::AppState;
+
+// check the admin token of the requests to the API
+// 401 if the token is missing or wrong, the API is open if no token is configured.
+// the Bearer scheme is matched ignoring case, the tokens are compared without their surrounding whitespace.
+// the token is compared in constant time so its value can not be guessed from the response times.
+pub async fn admin_auth(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let authorized = match &state.config.read().await.admin_token {
+        Some(token) => request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim_start().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .is_some_and(|(_, given)| {
+                bool::from(given.trim().as_bytes().ct_eq(token.trim().as_bytes()))
+            }),
+        None => true,
+    };
+    if !authorized {
+        debug!("request to the API without a valid admin token");
+        return (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response();
+    }
+    next.run(request).await
+}
@@ -1,25 +1,52 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
 use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use crate::index_cache::IndexCache;
+use crate::api::{add_etag, handler, service_unavailable};
+use crate::archive::{ArchivedEntry, MAGIC};
+use crate::cache::{freshness_lifetime, CacheEntry};
+use crate::index_cache::{headers_match_vary, normalize_query, surrogate_keys, vary_any};
 use crate::AppState;
+use ahash::{HashMap, HashSet};
 use aide::axum::IntoApiResponse;
-use axum::extract::{Path, Request};
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, Request};
 use axum::http::uri::PathAndQuery;
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::Response;
 use axum::{extract::State, response::IntoResponse, Json};
-use reqwest::header::HOST;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures_util::{stream, Stream, StreamExt};
+use reqwest::header::{CONTENT_TYPE, HOST, VARY};
 use reqwest::Method;
-use serde::Serialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 use tracing::{debug, warn};
 use uuid::Uuid;
 
 // handle get cache endpoint
 pub async fn cache_stats(State(state): State<AppState>) -> impl IntoApiResponse {
     debug!("new request to get cache stats");
+    // apply pending insertions and evictions so the size is accurate.
+    state.cache.run_pending_tasks().await;
+    let hits = state.metrics.cache_hits.load(Ordering::Relaxed);
+    let misses = state.metrics.cache_misses.load(Ordering::Relaxed);
     let stats = CacheStats {
         name: state.cache.name().unwrap_or_default().to_string(),
         entries: state.cache.entry_count(),
         size: state.cache.weighted_size(),
+        hits,
+        misses,
+        hit_ratio: if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        },
     };
     (StatusCode::OK, Json(stats))
 }
@@ -28,56 +55,221 @@ struct CacheStats {
     name: String,
     entries: u64,
     size: u64,
+    /// requests answered from the cache since the start.
+    hits: u64,
+    /// cacheable requests not found in the cache since the start.
+    misses: u64,
+    /// part of the cacheable requests answered from the cache, between 0 and 1.
+    hit_ratio: f64,
 }
 
+/// parse the uuid of a cache entry given in a path.
+/// A malformed uuid is a bad request, to not confuse it with an entry absent from the cache.
+fn parse_uuid(path: &str) -> Result<Uuid, StatusCode> {
+    Uuid::from_str(path).map_err(|_| {
+        warn!("request for invalid uuid");
+        StatusCode::BAD_REQUEST
+    })
+}
 // handle delete endpoint
-// will also delete from index by iterating over the entries to find the method/path
+// will also delete it from the index
 pub async fn delete_entry_per_uuid(
     Path(path): Path<String>,
     State(state): State<AppState>,
 ) -> impl IntoApiResponse {
     debug!("new request to delete a cache entry");
-    if let Ok(uuid) = Uuid::from_str(&path) {
-        state.cache.invalidate(&uuid).await;
-        state.index_cache.lock().await.delete_uuid_from_index(&uuid);
+    let uuid = match parse_uuid(&path) {
+        Ok(uuid) => uuid,
+        Err(status) => return status,
+    };
+    let removed = state.cache.remove(&uuid).await;
+    state.index_cache.delete_uuid_from_index(&uuid);
+    if removed.is_some() {
         debug!("cache entry removed");
         return StatusCode::OK;
     }
-    warn!("deletion request for invalid uuid");
     StatusCode::NOT_FOUND
 }
-// delete all entries for a given path and HOST, only for method GET
+// delete all entries for a given path and HOST, of every method or only the one given with ?method=
+// 400 if the path is malformed.
 pub async fn delete_entries_per_path(
     Path(path): Path<String>,
+    Query(filter): Query<MethodFilter>,
     State(state): State<AppState>,
     request: Request,
 ) -> impl IntoApiResponse {
     debug!("new request to delete a cache entry");
-    let path = ["/", &path].concat();
-    let mut index_cache = state.index_cache.lock().await;
-    let mut to_delete = vec![];
     let host = if let Some(host) = request.headers().get(HOST) {
-        host
+        host.clone()
     } else {
-        return StatusCode::NOT_FOUND;
-    };
-    if let Some(vec) = index_cache.get(&(
-        Method::GET,
-        PathAndQuery::from_str(&path).unwrap(),
-        host.clone(),
-    )) {
-        for e in vec {
-            state.cache.invalidate(&e.0).await;
-            to_delete.push(e.0);
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Ok(path) = PathAndQuery::from_str(&["/", &path].concat()) else {
+        warn!("deletion request for a malformed path");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let path = key_path(&state, path).await;
+    let method = match filter.method.map(|m| Method::from_str(&m.to_uppercase())) {
+        Some(Ok(method)) => Some(method),
+        Some(Err(_)) => {
+            warn!("deletion request for an invalid method");
+            return StatusCode::BAD_REQUEST.into_response();
         }
-    }
-    if !to_delete.is_empty() {
-        to_delete
+        None => None,
+    };
+    let key = format!(
+        "path {} {path} {}",
+        String::from_utf8_lossy(host.as_bytes()),
+        method.as_ref().map_or("*", Method::as_str)
+    );
+    deduplicated_purge(&state, key, async {
+        let signatures: Vec<_> = state
+            .index_cache
             .iter()
-            .for_each(|uuid| index_cache.delete_uuid_from_index(uuid));
-        return StatusCode::OK;
+            .filter(|e| {
+                let (m, p, h) = e.key();
+                *p == path && *h == host && method.as_ref().is_none_or(|method| method == m)
+            })
+            .map(|e| e.key().clone())
+            .collect();
+        let mut removed = false;
+        for signature in signatures {
+            if let Some(entries) = state.index_cache.delete_signature(&signature) {
+                removed = true;
+                for (uuid, _) in entries {
+                    state.cache.invalidate(&uuid).await;
+                }
+            }
+        }
+        if removed {
+            debug!("cache entries removed");
+            return StatusCode::OK;
+        }
+        StatusCode::NOT_FOUND
+    })
+    .await
+}
+/// method of the entries to delete, every method if none.
+#[derive(Deserialize, JsonSchema)]
+pub struct MethodFilter {
+    method: Option<String>,
+}
+// handle tag purge endpoint
+// delete every entry tagged by the backend service with this Surrogate-Key, whatever its signature.
+pub async fn delete_entries_per_tag(
+    Path(tag): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoApiResponse {
+    debug!("new request to delete the cache entries of a tag");
+    let key = format!("tag {tag}");
+    deduplicated_purge(&state, key, async {
+        // entries expired but not yet removed from the index do not count.
+        let uuids = state.index_cache.delete_tag(&tag);
+        let cached = uuids
+            .into_iter()
+            .filter(|uuid| state.cache.contains_key(uuid))
+            .collect::<Vec<_>>();
+        if cached.is_empty() {
+            return StatusCode::NOT_FOUND;
+        }
+        for uuid in cached {
+            state.cache.invalidate(&uuid).await;
+        }
+        debug!("cache entries removed");
+        StatusCode::OK
+    })
+    .await
+}
+/// signature of a request as it is stored in the index.
+#[derive(Deserialize, JsonSchema)]
+pub struct Signature {
+    method: String,
+    path: String,
+    host: String,
+}
+// delete the entries matching the signature method/path/host of a request, without knowing the uuid.
+// every variation of the Vary headers for this signature will be deleted.
+pub async fn delete_entries_per_signature(
+    Query(signature): Query<Signature>,
+    State(state): State<AppState>,
+) -> impl IntoApiResponse {
+    debug!("new request to delete cache entries from a request signature");
+    let (Ok(method), Ok(path), Ok(host)) = (
+        Method::from_str(&signature.method.to_uppercase()),
+        PathAndQuery::from_str(&signature.path),
+        HeaderValue::from_str(&signature.host),
+    ) else {
+        warn!("deletion request for invalid signature");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let path = key_path(&state, path).await;
+    let key = format!(
+        "signature {method} {path} {}",
+        String::from_utf8_lossy(host.as_bytes())
+    );
+    deduplicated_purge(&state, key, async {
+        let entries = state.index_cache.delete_signature(&(method, path, host));
+        if let Some(entries) = entries {
+            for (uuid, _) in entries {
+                state.cache.invalidate(&uuid).await;
+            }
+            debug!("cache entries removed");
+            return StatusCode::OK;
+        }
+        StatusCode::NOT_FOUND
+    })
+    .await
+}
+/// path as it is stored in the index, with the query normalized if configured.
+async fn key_path(state: &AppState, path: PathAndQuery) -> PathAndQuery {
+    if state.config.read().await.normalize_query {
+        normalize_query(&path)
+    } else {
+        path
+    }
+}
+/// run a purge, unless an identical one was already done within the deduplication window.
+/// In this case, the status of the previous purge is returned without scanning the index again.
+/// If too many purges are already running, 503 is returned.
+async fn deduplicated_purge(
+    state: &AppState,
+    key: String,
+    purge: impl Future<Output = StatusCode>,
+) -> Response {
+    let (window, retry_after) = {
+        let config = state.config.read().await;
+        (
+            Duration::from_millis(config.purge_dedup_window),
+            config.retry_after,
+        )
+    };
+    if let Some(status) = state.recent_purges.get(&key, window).await {
+        debug!("identical purge done recently, skipping it");
+        return status.into_response();
+    }
+    let Ok(_permit) = state.purge_permits.try_acquire() else {
+        warn!("too many purges at the same time, refusing this one");
+        return service_unavailable(retry_after);
+    };
+    let status = purge.await;
+    state.recent_purges.insert(key, status).await;
+    status.into_response()
+}
+/// purges done recently with their status, keyed by their parameters.
+#[derive(Clone, Default)]
+pub struct RecentPurges(Arc<Mutex<HashMap<String, (Instant, StatusCode)>>>);
+
+impl RecentPurges {
+    /// status of an identical purge done within the window.
+    /// purges older than the window are forgotten.
+    async fn get(&self, key: &str, window: Duration) -> Option<StatusCode> {
+        let mut purges = self.0.lock().await;
+        purges.retain(|_, (date, _)| date.elapsed() < window);
+        purges.get(key).map(|(_, status)| *status)
+    }
+    async fn insert(&self, key: String, status: StatusCode) {
+        self.0.lock().await.insert(key, (Instant::now(), status));
     }
-    StatusCode::NOT_FOUND
 }
 // handle raw entry endpoint
 // will return the raw data of a cache entry
@@ -87,19 +279,467 @@ pub async fn get_cache_entry(
     State(state): State<AppState>,
 ) -> impl IntoApiResponse {
     debug!("new request to return a raw cache entry");
-    if let Ok(uuid) = Uuid::from_str(&path) {
-        if let Some(entry) = state.cache.get(&uuid).await {
-            return entry.into_response();
-        }
+    let uuid = match parse_uuid(&path) {
+        Ok(uuid) => uuid,
+        Err(status) => return status.into_response(),
+    };
+    if let Some(entry) = state.cache.get(&uuid).await {
+        return entry.into_response();
     }
-    warn!("deletion request for invalid uuid");
+    debug!("no cache entry for this uuid");
     StatusCode::NOT_FOUND.into_response()
 }
+// handle entry metadata endpoint
+// will return information about a cache entry without its body.
+pub async fn get_cache_entry_metadata(
+    Path(path): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoApiResponse {
+    debug!("new request to return the metadata of a cache entry");
+    let uuid = match parse_uuid(&path) {
+        Ok(uuid) => uuid,
+        Err(status) => return status.into_response(),
+    };
+    // the stored entry is read as is, its size is the one taken in the cache.
+    let Some(stored) = state.cache.0.get(&uuid).await else {
+        debug!("no cache entry for this uuid");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let stored_size = stored.stored_size();
+    let Some(entry) = stored.decompressed() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let metadata = EntryMetadata {
+        uuid,
+        status: entry.status.as_u16(),
+        size: entry.body.len(),
+        stored_size,
+        fetched_at: entry
+            .fetched_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        age: entry.age().as_secs(),
+        freshness: entry.freshness.map(|f| f.as_secs()),
+    };
+    Json(metadata).into_response()
+}
+#[derive(Serialize)]
+struct EntryMetadata {
+    uuid: Uuid,
+    status: u16,
+    /// size of the body in bytes
+    size: usize,
+    /// size in bytes the entry takes in the cache
+    stored_size: u32,
+    /// unix timestamp in seconds of the last fetch from the backend service
+    fetched_at: u64,
+    /// seconds since the last fetch from the backend service
+    age: u64,
+    /// freshness lifetime in seconds, none if the entry only expires from inactivity
+    freshness: Option<u64>,
+}
+/// pagination and filter of the entries listing.
+#[derive(Deserialize, JsonSchema)]
+pub struct EntriesQuery {
+    /// maximum number of entries returned, 100 by default.
+    limit: Option<usize>,
+    /// number of entries skipped.
+    #[serde(default)]
+    offset: usize,
+    /// only list the entries of this HOST.
+    host: Option<String>,
+}
+// handle entries listing endpoint
+// returns the entries of the index with their signature, sorted by HOST, path and method.
+// entries evicted from the cache but still in the index are skipped.
+pub async fn list_cache_entries(
+    Query(query): Query<EntriesQuery>,
+    State(state): State<AppState>,
+) -> impl IntoApiResponse {
+    debug!("new request to list the cache entries");
+    let mut signatures: Vec<_> = state
+        .index_cache
+        .iter()
+        .filter(|e| {
+            query
+                .host
+                .as_ref()
+                .is_none_or(|host| e.key().2.as_bytes() == host.as_bytes())
+        })
+        .flat_map(|e| {
+            let (method, path, host) = e.key();
+            e.value()
+                .iter()
+                .map(|(uuid, vary)| ListedEntry {
+                    uuid: *uuid,
+                    method: method.to_string(),
+                    path: path.to_string(),
+                    host: String::from_utf8_lossy(host.as_bytes()).into_owned(),
+                    size: 0,
+                    vary_headers: vary
+                        .iter()
+                        .map(|(name, value)| {
+                            (
+                                name.to_string(),
+                                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                            )
+                        })
+                        .collect(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    signatures.sort_by(|a, b| (&a.host, &a.path, &a.method).cmp(&(&b.host, &b.path, &b.method)));
+    // contains_key and iter do not reset the idle timer of the entries.
+    signatures.retain(|entry| state.cache.0.contains_key(&entry.uuid));
+    let total = signatures.len();
+    let mut entries: Vec<_> = signatures
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(100))
+        .collect();
+    let page: HashSet<_> = entries.iter().map(|entry| entry.uuid).collect();
+    // the stored entry is read as is, the body is not decompressed.
+    let sizes: HashMap<_, _> = state
+        .cache
+        .0
+        .iter()
+        .filter(|(uuid, _)| page.contains(uuid.as_ref()))
+        .map(|(uuid, stored)| (*uuid, stored.stored_size()))
+        .collect();
+    for entry in entries.iter_mut() {
+        entry.size = sizes.get(&entry.uuid).copied().unwrap_or_default();
+    }
+    (StatusCode::OK, Json(EntriesPage { total, entries }))
+}
+#[derive(Serialize)]
+struct EntriesPage {
+    /// number of entries matching the filter, before pagination.
+    total: usize,
+    entries: Vec<ListedEntry>,
+}
+#[derive(Serialize)]
+struct ListedEntry {
+    uuid: Uuid,
+    method: String,
+    path: String,
+    host: String,
+    /// size in bytes the entry takes in the cache
+    size: u32,
+    /// request headers of this variation, from the Vary header of the response.
+    vary_headers: BTreeMap<String, String>,
+}
+/// response to store in the cache for a request signature, without requesting a backend service.
+#[derive(Deserialize, JsonSchema)]
+pub struct PrimedEntry {
+    method: String,
+    path: String,
+    host: String,
+    status: u16,
+    /// headers of the response as name and value pairs, a name can be repeated like for several Set-Cookie.
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    /// headers of the request the entry answers, as name and value pairs.
+    /// The variation of the entry is selected from them with the Vary header of the response, like for a response of a backend service.
+    #[serde(default)]
+    request_headers: Vec<(String, String)>,
+    /// body encoded in base64
+    #[serde(default)]
+    body: String,
+}
+/// number of requests of a warm-up sent to the backend services at the same time.
+const WARM_CONCURRENCY: usize = 8;
+/// request to send through the cache to warm it.
+#[derive(Deserialize, JsonSchema)]
+pub struct WarmTarget {
+    host: String,
+    path: String,
+}
+#[derive(Serialize)]
+struct WarmSummary {
+    /// requests answered with a success status, from the backend service or the cache.
+    succeeded: usize,
+    failed: Vec<WarmFailure>,
+}
+#[derive(Serialize)]
+struct WarmFailure {
+    host: String,
+    path: String,
+    /// status of the response, 400 if the host or path is malformed.
+    status: u16,
+}
+// handle cache warm-up endpoint
+// send GET requests through the cache like clients would, so their responses are cached before being asked for.
+// returns the number of successful requests and the failed ones.
+pub async fn warm_cache(
+    State(state): State<AppState>,
+    Json(targets): Json<Vec<WarmTarget>>,
+) -> impl IntoApiResponse {
+    debug!(
+        "new request to warm the cache with {} requests",
+        targets.len()
+    );
+    let statuses = stream::iter(targets)
+        .map(|target| {
+            let state = state.clone();
+            async move {
+                let status = match warm_request(&target) {
+                    Some(request) => handler(State(state), None, None, request)
+                        .await
+                        .into_response()
+                        .status(),
+                    None => StatusCode::BAD_REQUEST,
+                };
+                (target, status)
+            }
+        })
+        .buffer_unordered(WARM_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+    let mut summary = WarmSummary {
+        succeeded: 0,
+        failed: Vec::new(),
+    };
+    for (target, status) in statuses {
+        if status.is_success() {
+            summary.succeeded += 1;
+        } else {
+            summary.failed.push(WarmFailure {
+                host: target.host,
+                path: target.path,
+                status: status.as_u16(),
+            });
+        }
+    }
+    (StatusCode::OK, Json(summary))
+}
+/// GET request of a warm-up target, none if its host or path is malformed.
+fn warm_request(target: &WarmTarget) -> Option<Request> {
+    let path = PathAndQuery::from_str(&target.path).ok()?;
+    let host = HeaderValue::from_str(&target.host).ok()?;
+    let mut request = Request::new(Body::empty());
+    *request.uri_mut() = path.as_str().parse().ok()?;
+    request.headers_mut().insert(HOST, host);
+    Some(request)
+}
+// handle cache priming endpoint
+// insert an entry in the cache and index as if it was the response of the backend service.
+// returns the uuid of the new entry.
+pub async fn prime_entry(
+    State(state): State<AppState>,
+    Json(primed): Json<PrimedEntry>,
+) -> impl IntoApiResponse {
+    debug!("new request to prime a cache entry");
+    let (Ok(method), Ok(path), Ok(host), Ok(status), Ok(body)) = (
+        Method::from_str(&primed.method.to_uppercase()),
+        PathAndQuery::from_str(&primed.path),
+        HeaderValue::from_str(&primed.host),
+        StatusCode::from_u16(primed.status),
+        BASE64_STANDARD.decode(&primed.body),
+    ) else {
+        warn!("priming request with invalid entry");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let (Some(mut headers), Some(req_headers)) = (
+        header_map(primed.headers),
+        header_map(primed.request_headers),
+    ) else {
+        warn!("priming request with invalid header");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    // the variation of the entry, from the request headers named by its Vary header.
+    if vary_any(headers.get_all(VARY)) {
+        warn!("priming request for an entry varying on anything, it can not be cached");
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let Ok(mut vary) = headers_match_vary(&req_headers, headers.get_all(VARY)) else {
+        warn!("priming request with malformed Vary header");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let uuid = Uuid::new_v4();
+    let backend_etag = add_etag(&mut headers, &uuid);
+    let path = key_path(&state, path).await;
+    // the settings of the endpoint of the entry, as for a response of its backend service.
+    let (cache_config, always_vary_on) = {
+        let config = state.config.read().await;
+        (
+            config.cache_config(&Some(host.clone()), &path),
+            config.cache.always_vary_on(),
+        )
+    };
+    // headers configured to always be part of the cache key
+    for name in always_vary_on {
+        if let Some(value) = req_headers.get(&name) {
+            vary.insert(name, value.clone());
+        }
+    }
+    if body.len() as u64 > cache_config.max_cacheable_body_bytes {
+        warn!("priming request with a body too large to be cached");
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    let freshness = freshness_lifetime(&headers, &cache_config);
+    let mut entry = CacheEntry::new(status, headers, body.into(), backend_etag, freshness);
+    entry.expiration = Some(Duration::from_secs(cache_config.expiration));
+    entry.stale_if_error = cache_config.stale_if_error();
+    let removed = state.index_cache.add_entry(
+        uuid,
+        method,
+        path,
+        host,
+        vary,
+        cache_config.max_variants_per_key,
+    );
+    for removed in removed {
+        state.cache.invalidate(&removed).await;
+    }
+    state
+        .index_cache
+        .tag_entry(uuid, surrogate_keys(&entry.headers));
+    state.cache.insert(uuid, entry).await;
+    debug!("cache entry primed");
+    (StatusCode::CREATED, uuid.to_string()).into_response()
+}
+/// headers from name and value pairs, repeated names are kept. None if one is invalid.
+fn header_map(pairs: Vec<(String, String)>) -> Option<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        headers.append(
+            HeaderName::from_str(&name).ok()?,
+            HeaderValue::from_str(&value).ok()?,
+        );
+    }
+    Some(headers)
+}
+// handle export endpoint
+// streams an archive of every entry of the cache with its signature.
+pub async fn export_cache(State(state): State<AppState>) -> impl IntoApiResponse {
+    debug!("new request to export the cache");
+    let archive = archive_cache(&state).await.map(Ok::<_, Infallible>);
+    (
+        [(CONTENT_TYPE, "application/octet-stream")],
+        Body::from_stream(archive),
+    )
+        .into_response()
+}
+/// archive of every entry of the cache, entries evicted while it is streamed are skipped.
+pub(crate) async fn archive_cache(state: &AppState) -> impl Stream<Item = Bytes> {
+    let snapshot: Vec<_> = state
+        .index_cache
+        .iter()
+        .flat_map(|signature| {
+            let ((method, path, host), variants) = signature.pair();
+            variants
+                .iter()
+                .map(|(uuid, vary)| {
+                    (
+                        method.clone(),
+                        path.clone(),
+                        host.clone(),
+                        *uuid,
+                        vary.clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let cache = state.cache.clone();
+    let entries = stream::iter(snapshot).filter_map(move |(method, path, host, uuid, vary)| {
+        let cache = cache.clone();
+        async move {
+            let entry = cache.get(&uuid).await?;
+            let archived = ArchivedEntry {
+                uuid,
+                method,
+                path,
+                host,
+                vary,
+                status: entry.status,
+                headers: entry.headers,
+                body: entry.body,
+                backend_etag: entry.backend_etag,
+                fetched_at: entry.fetched_at,
+                freshness: entry.freshness,
+            };
+            Some(archived.encode())
+        }
+    });
+    stream::once(async { Bytes::from_static(MAGIC) }).chain(entries)
+}
+// handle import endpoint
+// load the entries of an archive made by the export endpoint, they keep their uuid.
+// returns the number of entries imported.
+pub async fn import_cache(State(state): State<AppState>, request: Request) -> impl IntoApiResponse {
+    debug!("new request to import entries in the cache");
+    // an archive larger than the cache could not fit in it anyway.
+    let limit = (state.config.read().await.cache.size_limit * 1024 * 1024) as usize;
+    let mut body = request.into_body().into_data_stream();
+    let mut archive = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let Ok(chunk) = chunk else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        if archive.len() + chunk.len() > limit {
+            warn!("import request with an archive larger than the cache");
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+        archive.extend_from_slice(&chunk);
+    }
+    let archive = Bytes::from(archive);
+    let Some(entries) = ArchivedEntry::decode_all(archive) else {
+        warn!("import request with a malformed archive");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let imported = import_entries(&state, entries).await;
+    debug!("{imported} entries imported");
+    (StatusCode::OK, imported.to_string()).into_response()
+}
+/// add archived entries to the cache, returns the number of entries added.
+/// Entries keep their age, those stale without a validator to revalidate them or too large to be cached are skipped.
+pub(crate) async fn import_entries(state: &AppState, entries: Vec<ArchivedEntry>) -> usize {
+    let config = state.config.read().await.clone();
+    let mut imported = 0;
+    for archived in entries {
+        let cache_config = config.cache_config(&Some(archived.host.clone()), &archived.path);
+        let mut entry = CacheEntry::new(
+            archived.status,
+            archived.headers,
+            archived.body,
+            archived.backend_etag,
+            archived.freshness,
+        );
+        entry.fetched_at = archived.fetched_at;
+        if (entry.is_stale() && !entry.can_revalidate())
+            || entry.body.len() as u64 > cache_config.max_cacheable_body_bytes
+        {
+            continue;
+        }
+        entry.expiration = Some(Duration::from_secs(cache_config.expiration));
+        entry.stale_if_error = cache_config.stale_if_error();
+        let removed = state.index_cache.add_entry(
+            archived.uuid,
+            archived.method,
+            archived.path,
+            archived.host,
+            archived.vary,
+            cache_config.max_variants_per_key,
+        );
+        for removed in removed {
+            state.cache.invalidate(&removed).await;
+        }
+        state
+            .index_cache
+            .tag_entry(archived.uuid, surrogate_keys(&entry.headers));
+        state.cache.insert(archived.uuid, entry).await;
+        imported += 1;
+    }
+    imported
+}
 // handle delete_all endpoint
 pub async fn delete_entries(State(state): State<AppState>) -> impl IntoApiResponse {
     debug!("new request to delete all cache entries");
     state.cache.invalidate_all();
-    *state.index_cache.lock().await = IndexCache::new();
+    state.index_cache.clear();
     debug!("all cache cleared");
     StatusCode::OK
 }
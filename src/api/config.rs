@@ -1,9 +1,20 @@
+use std::str::FromStr;
+use std::time::Duration;
+
 use aide::axum::IntoApiResponse;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
+use axum::http::uri::PathAndQuery;
+use axum::http::HeaderValue;
+use axum::response::IntoResponse;
+use axum::Json;
+use moka::ops::compute::Op;
 use reqwest::StatusCode;
-use tracing::debug;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tracing::{debug, warn};
 use url::Url;
 
+use crate::config::{Config, Endpoint};
 use crate::AppState;
 
 // handle delete endpoint
@@ -12,18 +23,19 @@ pub async fn delete_endpoint(
     State(state): State<AppState>,
 ) -> impl IntoApiResponse {
     debug!("new request to delete an endpoint in configuration");
-    if let Some(index) = state
-        .config
-        .lock()
-        .await
+    let mut config = state.config.write().await;
+    if let Some(index) = config
         .endpoints
         .iter()
-        .position(|x| *x.0 == path)
+        .position(|x| x.host == path && x.prefix.is_none())
     {
         // delete endpoint
-        state.config.lock().await.endpoints.remove(index);
-        // write config
-
+        let endpoint = config.endpoints.remove(index);
+        write_config(&state, &config);
+        if config.invalidate_on_config_change {
+            drop(config);
+            invalidate_host(&state, &endpoint).await;
+        }
         // return success
         return StatusCode::OK;
     }
@@ -31,49 +43,201 @@ pub async fn delete_endpoint(
     StatusCode::NOT_FOUND
 }
 // handle add endpoint
+// body is the url of the backend service, it replaces the backend services of an existing endpoint for this HOST without prefix.
+// the other settings of that endpoint are kept.
+// the HOST can be a pattern, 400 if it is invalid or if the configuration would not be valid, like for a url pointing to Mnemosyne itself.
 pub async fn add_endpoint(
     Path(path): Path<String>,
     State(state): State<AppState>,
+    body: String,
 ) -> impl IntoApiResponse {
-    debug!("new request to delete an endpoint in configuration");
-    if let Some(index) = state
-        .config
-        .lock()
-        .await
+    debug!("new request to add an endpoint in configuration");
+    let Ok(url) = Url::parse(body.trim()) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let mut endpoint = Endpoint::new(path.clone(), url);
+    if endpoint.compile().is_err() {
+        warn!("invalid pattern for the endpoint {path}");
+        return StatusCode::BAD_REQUEST;
+    }
+    let mut config = state.config.write().await;
+    let mut updated = config.clone();
+    let modified = if let Some(e) = updated
         .endpoints
-        .iter()
-        .position(|x| *x.0 == path)
+        .iter_mut()
+        .find(|x| x.host == path && x.prefix.is_none())
     {
-        // delete endpoint
-        state.config.lock().await.endpoints.remove(index);
-        // write config
-
-        // return success
-        return StatusCode::OK;
+        e.backends = endpoint.backends;
+        Some(e.clone())
+    } else {
+        updated.endpoints.push(endpoint);
+        None
+    };
+    if let Err(err) = updated.validate() {
+        warn!("the endpoint {path} is refused: {err:#}");
+        return StatusCode::BAD_REQUEST;
+    }
+    *config = updated;
+    write_config(&state, &config);
+    if let Some(endpoint) = modified.filter(|_| config.invalidate_on_config_change) {
+        drop(config);
+        invalidate_host(&state, &endpoint).await;
+    }
+    StatusCode::OK
+}
+/// write the modified configuration to the file it was loaded from, if any.
+/// The change is kept in memory even if the file can not be written.
+fn write_config(state: &AppState, config: &Config) {
+    if let Some(path) = &state.config_path {
+        if let Err(err) = confy::store_path(path.as_path(), config) {
+            warn!(
+                "the configuration could not be written to {}",
+                path.display()
+            );
+            debug!("{err}");
+        }
+    }
+}
+/// invalidate every cached entry of the HOSTs of an endpoint, its responses came from a backend service no longer configured.
+/// The HOSTs are matched like the requests are routed: ignoring case, or with the pattern of the endpoint.
+async fn invalidate_host(state: &AppState, endpoint: &Endpoint) {
+    debug!("invalidating the cache entries of {}", endpoint.host);
+    let uuids = state.index_cache.delete_host(|host| endpoint.matches(host));
+    for uuid in uuids {
+        state.cache.invalidate(&uuid).await;
     }
-    // return not found
-    StatusCode::NOT_FOUND
 }
+// handle set fallback endpoint
+// body is the url of the backend service, 400 if the configuration would not be valid, like for a url pointing to Mnemosyne itself.
 pub async fn set_fallback_value(
     State(state): State<AppState>,
     body: String,
 ) -> impl IntoApiResponse {
     debug!("new request to set the fallback in configuration");
-    if let Ok(url) = Url::parse(&body) {
-        state.config.lock().await.fall_back_endpoint = url;
+    let Ok(url) = Url::parse(body.trim()) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let mut config = state.config.write().await;
+    let mut updated = config.clone();
+    updated.fall_back_endpoint = url;
+    if let Err(err) = updated.validate() {
+        warn!("the fallback is refused: {err:#}");
+        return StatusCode::BAD_REQUEST;
     }
-    // return not found
-    StatusCode::NOT_FOUND
+    *config = updated;
+    write_config(&state, &config);
+    StatusCode::OK
 }
 pub async fn get_fallback_value(State(state): State<AppState>) -> impl IntoApiResponse {
     debug!("new request to get the fallback in configuration");
-    let body = &state.config.lock().await.fall_back_endpoint;
-    // return not found
-    (StatusCode::NOT_FOUND, body.to_string())
+    let body = &state.config.read().await.fall_back_endpoint;
+    (StatusCode::OK, body.to_string())
+}
+// handle cache expiration endpoint
+// body is the time in seconds before unused entries are deleted, 400 if it is not a number above 0.
+// the entries already cached keep their place, their expiration is updated.
+pub async fn set_expiration(State(state): State<AppState>, body: String) -> impl IntoApiResponse {
+    debug!("new request to set the cache expiration in configuration");
+    let Some(expiration) = body.trim().parse::<u64>().ok().filter(|e| *e > 0) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let mut config = state.config.write().await;
+    config.cache.expiration = expiration;
+    write_config(&state, &config);
+    let config = config.downgrade();
+    // an endpoint can override the expiration of its entries.
+    let entries = state
+        .index_cache
+        .iter()
+        .map(|e| {
+            let (_, path, host) = e.key();
+            let expiration = config.cache_config(&Some(host.clone()), path).expiration;
+            let uuids = e.value().iter().map(|(uuid, _)| *uuid).collect::<Vec<_>>();
+            (Duration::from_secs(expiration), uuids)
+        })
+        .collect::<Vec<_>>();
+    drop(config);
+    for (expiration, uuids) in entries {
+        for uuid in uuids {
+            // the stored entry is updated as is, its body stays compressed.
+            // an entry deleted in the meantime is not added back.
+            state
+                .cache
+                .entry(uuid)
+                .and_compute_with(|entry| async move {
+                    match entry {
+                        Some(entry) => {
+                            let mut entry = entry.into_value();
+                            entry.expiration = Some(expiration);
+                            Op::Put(entry)
+                        }
+                        None => Op::Nop,
+                    }
+                })
+                .await;
+        }
+    }
+    StatusCode::OK
+}
+pub async fn get_expiration(State(state): State<AppState>) -> impl IntoApiResponse {
+    debug!("new request to get the cache expiration in configuration");
+    let expiration = state.config.read().await.cache.expiration;
+    (StatusCode::OK, expiration.to_string())
+}
+// handle maintenance endpoint
+// body is true to enable the read-only mode, false to disable it.
+pub async fn set_maintenance(State(state): State<AppState>, body: String) -> impl IntoApiResponse {
+    debug!("new request to set the maintenance mode in configuration");
+    if let Ok(maintenance) = body.trim().parse::<bool>() {
+        state.config.write().await.maintenance = maintenance;
+        return StatusCode::OK;
+    }
+    StatusCode::BAD_REQUEST
+}
+/// request for which the route is previewed.
+#[derive(Deserialize, JsonSchema)]
+pub struct RouteQuery {
+    host: Option<String>,
+    path: Option<String>,
+}
+// handle route preview endpoint
+// returns the url of the backend service a request would be sent to, without sending it.
+pub async fn preview_route(
+    Query(query): Query<RouteQuery>,
+    State(state): State<AppState>,
+) -> impl IntoApiResponse {
+    debug!("new request to preview the route of a request");
+    let path = query.path.as_deref().unwrap_or("/");
+    let Ok(path) = PathAndQuery::from_str(path) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let host = match query.host.as_deref().map(HeaderValue::from_str) {
+        Some(Ok(host)) => Some(host),
+        Some(Err(_)) => return StatusCode::BAD_REQUEST.into_response(),
+        None => None,
+    };
+    let url = state.config.read().await.to_backend_uri(&path, &host);
+    (StatusCode::OK, url.to_string()).into_response()
+}
+// handle list endpoints
+// returns every endpoint of the configuration, in the order of the configuration.
+// requests are matched to the endpoints with exactly their HOST first, then to those with a pattern, and to the longest prefix among them.
+pub async fn get_endpoints(State(state): State<AppState>) -> impl IntoApiResponse {
+    debug!("new request to list the endpoints in configuration");
+    let endpoints = state.config.read().await.endpoints.clone();
+    (StatusCode::OK, Json(endpoints))
 }
 // handle delete all  endpoints
 pub async fn delete_endpoints(State(state): State<AppState>) -> impl IntoApiResponse {
     debug!("new request to delete all endpoints in configuration");
-    state.config.lock().await.endpoints = Vec::new();
+    let mut config = state.config.write().await;
+    let endpoints = std::mem::take(&mut config.endpoints);
+    write_config(&state, &config);
+    if config.invalidate_on_config_change {
+        drop(config);
+        for endpoint in endpoints {
+            invalidate_host(&state, &endpoint).await;
+        }
+    }
     StatusCode::OK
 }
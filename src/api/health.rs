@@ -0,0 +1,61 @@
+use aide::axum::IntoApiResponse;
+use axum::extract::State;
+use axum::Json;
+use reqwest::StatusCode;
+use serde::Serialize;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::circuit_breaker::breaker_key;
+use crate::AppState;
+
+#[derive(Serialize)]
+struct Health {
+    /// seconds since the state of Mnemosyne was created.
+    uptime_secs: u64,
+    /// number of endpoints configured.
+    endpoints: usize,
+}
+
+// handle health endpoint
+// Mnemosyne is alive as long as it answers.
+pub async fn health(State(state): State<AppState>) -> impl IntoApiResponse {
+    debug!("new request for the health");
+    let endpoints = state.config.read().await.endpoints.len();
+    let health = Health {
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        endpoints,
+    };
+    (StatusCode::OK, Json(health))
+}
+
+// handle ready endpoint
+// Mnemosyne is ready if every endpoint has a backend service with a closed circuit breaker, 503 otherwise.
+// The fallback backend service is checked too, unless strict_routing is set.
+// Without breaker_threshold the failures of the backend services are not tracked, Mnemosyne is always ready.
+pub async fn ready(State(state): State<AppState>) -> impl IntoApiResponse {
+    debug!("new request for the readiness");
+    let config = state.config.read().await;
+    let Some((_, cooldown)) = config.circuit_breaker() else {
+        return StatusCode::OK;
+    };
+    let available =
+        |url: &Url| breaker_key(url).is_none_or(|key| !state.breakers.is_open(&key, cooldown));
+    if let Some(endpoint) = config.endpoints.iter().find(|endpoint| {
+        !endpoint
+            .backends
+            .iter()
+            .any(|b| b.weight > 0 && available(&b.url))
+    }) {
+        warn!(
+            "every backend service of the endpoint {} has an open circuit breaker, not ready",
+            endpoint.host
+        );
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+    if !config.strict_routing && !available(&config.fall_back_endpoint) {
+        warn!("the fallback backend service has an open circuit breaker, not ready");
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+    StatusCode::OK
+}
@@ -0,0 +1,140 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use aide::axum::IntoApiResponse;
+use axum::body::HttpBody;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::Response;
+use tracing::debug;
+
+use crate::AppState;
+
+/// counters updated by the handlers, exported by the metrics endpoint and the cache statistics.
+#[derive(Default)]
+pub struct Metrics {
+    /// lookups where the index had a uuid absent from the cache.
+    pub orphan_lookups: AtomicU64,
+    /// requests answered from the cache, including 304 responses.
+    pub cache_hits: AtomicU64,
+    /// cacheable requests not found in the cache.
+    pub cache_misses: AtomicU64,
+    /// requests sent to the backend services.
+    pub backend_requests: AtomicU64,
+    /// requests to the backend services that failed or whose body could not be read.
+    pub backend_errors: AtomicU64,
+    /// bytes of body served from the cache.
+    pub served_bytes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    /// count a response served from the cache.
+    pub fn hit(&self, rep: &Response) {
+        Self::inc(&self.cache_hits);
+        let bytes = rep.body().size_hint().exact().unwrap_or_default();
+        self.served_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+// handle metrics endpoint
+// returns the metrics in the Prometheus text exposition format.
+pub async fn metrics(State(state): State<AppState>) -> impl IntoApiResponse {
+    debug!("new request for the metrics");
+    // apply pending insertions and evictions so the number of entries is accurate.
+    state.cache.run_pending_tasks().await;
+    let (keys, variants) = {
+        let index = &state.index_cache;
+        (
+            index.len(),
+            index.iter().map(|e| e.value().len()).sum::<usize>(),
+        )
+    };
+    let variants_per_key = if keys == 0 {
+        0.0
+    } else {
+        variants as f64 / keys as f64
+    };
+    let mut body = String::new();
+    metric(
+        &mut body,
+        "mnemosyne_index_keys",
+        "gauge",
+        "Number of request signatures in the index.",
+        keys,
+    );
+    metric(
+        &mut body,
+        "mnemosyne_index_variants",
+        "gauge",
+        "Number of variations of requests in the index.",
+        variants,
+    );
+    metric(
+        &mut body,
+        "mnemosyne_index_variants_per_key",
+        "gauge",
+        "Average number of variations per request signature.",
+        variants_per_key,
+    );
+    metric(
+        &mut body,
+        "mnemosyne_index_orphan_lookups_total",
+        "counter",
+        "Lookups where the index had an entry absent from the cache.",
+        state.metrics.orphan_lookups.load(Ordering::Relaxed),
+    );
+    let counters = [
+        (
+            "mnemosyne_cache_hits_total",
+            "Requests answered from the cache.",
+            &state.metrics.cache_hits,
+        ),
+        (
+            "mnemosyne_cache_misses_total",
+            "Cacheable requests not found in the cache.",
+            &state.metrics.cache_misses,
+        ),
+        (
+            "mnemosyne_backend_requests_total",
+            "Requests sent to the backend services.",
+            &state.metrics.backend_requests,
+        ),
+        (
+            "mnemosyne_backend_errors_total",
+            "Requests to the backend services that failed.",
+            &state.metrics.backend_errors,
+        ),
+        (
+            "mnemosyne_cache_served_bytes_total",
+            "Bytes of body served from the cache.",
+            &state.metrics.served_bytes,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        metric(
+            &mut body,
+            name,
+            "counter",
+            help,
+            counter.load(Ordering::Relaxed),
+        );
+    }
+    metric(
+        &mut body,
+        "mnemosyne_cache_entries",
+        "gauge",
+        "Number of entries in the cache.",
+        state.cache.entry_count(),
+    );
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// write a metric with its HELP and TYPE lines.
+fn metric(body: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(body, "# HELP {name} {help}");
+    let _ = writeln!(body, "# TYPE {name} {kind}");
+    let _ = writeln!(body, "{name} {value}");
+}
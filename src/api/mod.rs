@@ -1,45 +1,81 @@
-use crate::index_cache::headers_match_vary;
+use crate::api::metrics::Metrics;
+use crate::cache::{
+    check_etag, check_if_range, check_modified_since, freshness_lifetime, CacheEntry,
+};
+use crate::cache_control::CacheControl;
+use crate::circuit_breaker::breaker_key;
+use crate::config::{Config, Listener, RetryPolicy};
+use crate::in_flight::FetchGuard;
+use crate::index_cache::{
+    headers_match_vary, normalize_query, surrogate_keys, vary_any, IndexCache, Signature,
+};
 use crate::AppState;
-use axum::body::to_bytes;
-use axum::extract::{Request, State};
-use axum::http::{uri::PathAndQuery, HeaderMap, HeaderValue};
-use axum::response::IntoResponse;
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{uri::PathAndQuery, HeaderMap, HeaderName, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
 use enclose::enc;
-use reqwest::header::{ETAG, HOST, VARY};
-use reqwest::StatusCode;
-use tokio::spawn;
+use futures_util::{stream, StreamExt};
+use httpdate::fmt_http_date;
+use reqwest::header::{
+    ACCEPT_RANGES, AGE, AUTHORIZATION, CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_RANGE,
+    DATE, ETAG, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION, RANGE, RETRY_AFTER, SET_COOKIE, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+    VARY, WARNING,
+};
+use reqwest::{Client, Method, StatusCode};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
 use tracing::{debug, info, trace, warn};
+use upgrade::{is_upgrade, proxy_upgrade};
+use url::Url;
 use uuid::Uuid;
 
+pub mod access_log;
+pub mod auth;
 pub mod cache;
 pub mod config;
+pub mod health;
+pub mod metrics;
+mod upgrade;
+
+/// date of the last fetch from the backend service of a cache entry served.
+const X_CACHE_DATE: HeaderName = HeaderName::from_static("x-cache-date");
+/// freshness lifetime in seconds of the entry created by a request, accepted only from allowlisted clients.
+const X_CACHE_TTL: HeaderName = HeaderName::from_static("x-cache-ttl");
+/// HIT if the response was served from the cache, MISS if it was fetched from the backend service.
+const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
 
 // handle request
-pub async fn handler(State(state): State<AppState>, request: Request) -> impl IntoResponse {
+pub async fn handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    listener: Option<Extension<Listener>>,
+    mut request: Request,
+) -> impl IntoResponse {
     debug!("new request for backend");
     trace!("{:?}", request);
-    // check if etag is present in headers
-    if state.cache.check_etag(request.headers()) {
-        // respond 304 if etag is present in cache
-        debug!("etag is valid, returning 304 status");
-        return StatusCode::NOT_MODIFIED.into_response();
-    }
-
-    // if response is in cache with valid header if any, return response from cache
-    let index = state.index_cache;
-    if let Some(uuid) = index.lock().await.request_to_uuid(&request) {
-        if let Some(rep) = state.cache.get(&uuid).await {
-            info!("cache entry is served");
-            return rep.into_response();
-        } else {
-            // present in index_cache but not in cache, it means it was automatically invalidated.
-            // must update index cache.
-            debug!("index was not updated, entry in cache was deleted automaticcaly");
-            index.lock().await.delete_uuid_from_index(&uuid);
+    if !request.headers().contains_key(HOST) {
+        let config = state.config.read().await;
+        if let Some(host) = &config.default_host {
+            debug!("request without HOST, using the default HOST");
+            match HeaderValue::from_str(host) {
+                Ok(host) => {
+                    request.headers_mut().insert(HOST, host);
+                }
+                Err(_) => warn!("default HOST is not a valid header value"),
+            }
+        } else if config.reject_missing_host {
+            debug!("request without HOST, refusing it");
+            return StatusCode::BAD_REQUEST.into_response();
         }
     }
-
-    // if not in cache, make the request to backend service
     let req_method = request.method().to_owned();
     let req_host = request.headers().get(HOST).cloned();
     let req_headers = request.headers().to_owned();
@@ -47,68 +83,840 @@ pub async fn handler(State(state): State<AppState>, request: Request) -> impl In
         .uri()
         .path_and_query()
         .cloned()
-        .unwrap_or(PathAndQuery::from_static(""));
-    debug!("response was not cached, requesting backend service");
-    let url_backend = state
-        .config
-        .lock()
-        .await
-        .to_backend_uri(&req_uri, &req_host);
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
+    let head = req_method == Method::HEAD;
+    let (mut passthrough, max_entry_age, always_vary_on, client_cache_control, normalize) = {
+        let config = state.config.read().await;
+        // the fallback can be disabled globally or only for the listener of the request.
+        // checked before the cache so responses of the fallback are not served where it is disabled.
+        let fallback = !config.strict_routing && listener.as_ref().is_none_or(|l| l.fallback);
+        if !fallback && config.find_endpoint(&req_host, &req_uri).is_none() {
+            debug!("unknown HOST without fallback, refusing the request");
+            return config.unknown_host_status.status().into_response();
+        }
+        // upgraded connections, like WebSocket, are relayed without the cache.
+        if is_upgrade(request.headers()) {
+            drop(config);
+            return proxy_upgrade(&state, connect_info.map(|c| c.0.ip()), &listener, request).await;
+        }
+        // requests with a method configured as non cacheable are passed through, the cache is not used at all.
+        // only GET and HEAD requests are idempotent, other methods are always passed through.
+        // requests with credentials are passed through too, unless the cache is allowed for them.
+        // every request is passed through if the cache is disabled.
+        (
+            !config.caching_enabled
+                || !matches!(req_method, Method::GET | Method::HEAD)
+                || config.cache.is_non_cacheable_method(&req_method)
+                || is_grpc_web(&req_headers)
+                || (req_headers.contains_key(AUTHORIZATION)
+                    && !config.cache.cache_authorized_requests)
+                || is_multi_range(&req_headers),
+            config.cache.max_entry_age(),
+            config.cache.always_vary_on(),
+            config.client_cache_control(&req_host, &req_uri),
+            config.normalize_query,
+        )
+    };
+    // path of the request in the cache key.
+    let key_uri = if normalize {
+        normalize_query(&req_uri)
+    } else {
+        req_uri.clone()
+    };
+    // signature of the entries of the request in the index, a HEAD request uses the ones of the GET request.
+    let signature = req_host.clone().map(|host| {
+        let method = if head {
+            Method::GET
+        } else {
+            req_method.clone()
+        };
+        (method, key_uri.clone(), host)
+    });
+
+    // if response is in cache with valid header if any, return response from cache
+    let index = state.index_cache.clone();
+    let mut stale = None;
+    // guard of the fetch from the backend service, identical concurrent requests wait for it.
+    let mut fetch = None;
+    let mut waited = false;
+    // a background revalidation holds the guard of its request already, joining it again would wait for itself.
+    let revalidation = request.extensions().get::<Revalidation>().is_some();
+    loop {
+        let uuid = if passthrough {
+            None
+        } else {
+            index.request_to_uuid(&request, &always_vary_on, normalize)
+        };
+        if let Some(uuid) = uuid {
+            if let Some(entry) = state.cache.get(&uuid).await {
+                // entries older than the maximum age are revalidated even if they are still fresh.
+                let too_old = max_entry_age.is_some_and(|max| entry.age() >= max);
+                // the client asks for a revalidated response, the entry is not served and no 304 is given without the backend service.
+                // same for the background revalidation of an entry served stale.
+                let no_cache = CacheControl::request_no_cache(&req_headers) || revalidation;
+                if !entry.is_stale() && !too_old && !no_cache {
+                    cache_event("hit", Some(&uuid), &req_method, &req_uri, &req_host);
+                    // respond 304 if the etag of the request is the one of this entry, or if it was not modified since the date of the request.
+                    if check_etag(&req_headers, &uuid, entry.backend_etag.as_ref())
+                        || check_modified_since(&req_headers, &entry)
+                    {
+                        debug!("validator of the request is valid, returning 304 status");
+                        let mut rep = StatusCode::NOT_MODIFIED.into_response();
+                        rep.headers_mut()
+                            .insert(X_CACHE, HeaderValue::from_static("HIT"));
+                        state.metrics.hit(&rep);
+                        return rep;
+                    }
+                    let rep = serve_entry(entry, &req_headers);
+                    let rep = if head { without_body(rep) } else { rep };
+                    state.metrics.hit(&rep);
+                    return with_cache_control(rep, &client_cache_control);
+                }
+                // within stale-while-revalidate, the stale entry is served and revalidated in the background, once at a time.
+                if entry.serve_while_revalidate() && !too_old && !no_cache {
+                    if let Some((method, path, host)) = signature.clone() {
+                        let variant =
+                            variant_headers(&index, &signature, &always_vary_on, &req_headers);
+                        if let Ok(guard) = state.in_flight.join((method, path, host, variant)) {
+                            debug!(
+                                "serving the stale cache entry, revalidating it in the background"
+                            );
+                            spawn_revalidation(&state, listener.clone(), &request, guard);
+                        }
+                    }
+                    cache_event("stale", Some(&uuid), &req_method, &req_uri, &req_host);
+                    let rep = serve_entry(entry, &req_headers);
+                    let rep = if head { without_body(rep) } else { rep };
+                    state.metrics.hit(&rep);
+                    return with_cache_control(rep, &client_cache_control);
+                }
+                // the entry must be revalidated by the backend service before being served again.
+                debug!("cache entry is stale, revalidating with the backend service");
+                stale = Some((uuid, entry));
+            } else {
+                // present in index_cache but not in cache, it means it was automatically invalidated.
+                // must update index cache.
+                debug!("index was not updated, entry in cache was deleted automaticcaly");
+                Metrics::inc(&state.metrics.orphan_lookups);
+                index.delete_uuid_from_index(&uuid);
+            }
+        }
+        // concurrent misses for the same request wait for the first one to be cached, once.
+        if let Some((method, path, host)) = signature
+            .clone()
+            .filter(|_| stale.is_none() && !passthrough && !head && !waited && !revalidation)
+        {
+            let variant = variant_headers(&index, &signature, &always_vary_on, &req_headers);
+            match state.in_flight.join((method, path, host, variant)) {
+                Ok(guard) => fetch = Some(guard),
+                Err(lock) => {
+                    debug!("same request already in flight, waiting for its response");
+                    let _ = lock.read().await;
+                    waited = true;
+                    continue;
+                }
+            }
+        }
+        break;
+    }
+    // a HEAD response has no body to store, it is only served from a fresh GET entry.
+    if head && stale.take().is_some() {
+        debug!("cache entry is stale, HEAD request is passed through");
+    }
+    passthrough |= head;
+
+    // if not in cache, make the request to backend service
+    if req_host.is_some() && !passthrough {
+        cache_event("miss", None, &req_method, &req_uri, &req_host);
+        Metrics::inc(&state.metrics.cache_misses);
+    } else {
+        // the response can not be cached without a HOST header.
+        cache_event("bypass", None, &req_method, &req_uri, &req_host);
+    }
+    let (
+        url_backend,
+        cache_config,
+        rewrite,
+        backend_idle_timeout,
+        retry,
+        breaker,
+        retry_after,
+        proto,
+        backend_host,
+    ) = {
+        let config = state.config.read().await;
+        if config.maintenance {
+            debug!("maintenance mode, the backend service is not requested");
+            return service_unavailable(config.retry_after);
+        }
+        // backend services with an open circuit breaker are skipped if the endpoint has others.
+        let cooldown = config.circuit_breaker().map(|(_, cooldown)| cooldown);
+        let url_backend = config.to_healthy_backend_uri(&req_uri, &req_host, |url| {
+            cooldown
+                .zip(breaker_key(url))
+                .is_none_or(|(cooldown, key)| !state.breakers.is_open(&key, cooldown))
+        });
+        if config.is_self_referential(&url_backend) {
+            warn!("the backend url points to Mnemosyne itself, refusing to loop");
+            return StatusCode::LOOP_DETECTED.into_response();
+        }
+        let rewrite = config
+            .find_endpoint(&req_host, &req_uri)
+            .map(|e| e.rewrite.clone());
+        let backend_host = config.backend_host(&req_host, &req_uri, &url_backend);
+        (
+            url_backend,
+            config.cache_config(&req_host, &req_uri),
+            rewrite,
+            config.backend_idle_timeout(),
+            config.retry_policy(),
+            config.circuit_breaker(),
+            config.retry_after,
+            forwarded_proto(&config, &listener),
+            backend_host,
+        )
+    };
+    let ttl_override = req_headers
+        .get(X_CACHE_TTL)
+        .filter(|_| cache_config.can_override_ttl(connect_info.map(|c| c.0.ip())))
+        .and_then(|ttl| ttl.to_str().ok()?.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
     debug!("Request URI retrieved: {req_uri}");
     debug!("Request URL transmitted:{url_backend}");
-    let req = state
-        .client
-        .request(request.method().to_owned(), url_backend)
-        .headers(request.headers().to_owned())
-        .body(to_bytes(request.into_body(), usize::MAX).await.unwrap())
-        .send()
-        .await;
+    // conditional request so the backend service does not send the body again if the stale entry is still valid.
+    let mut backend_headers = req_headers.clone();
+    backend_headers.remove(X_CACHE_TTL);
+    // the full response is cached, the range of the client is then served from it.
+    if !passthrough {
+        backend_headers.remove(RANGE);
+        backend_headers.remove(IF_RANGE);
+    }
+    strip_hop_by_hop(&mut backend_headers);
+    if let Some(host) = backend_host {
+        backend_headers.insert(HOST, host);
+    }
+    forwarding_headers(
+        &mut backend_headers,
+        connect_info.map(|c| c.0.ip()),
+        &req_host,
+        proto,
+    );
+    if let Some((_, entry)) = &stale {
+        // a 304 must answer the validators of the entry, not those of the client.
+        backend_headers.remove(IF_NONE_MATCH);
+        backend_headers.remove(IF_MODIFIED_SINCE);
+        if let Some(etag) = &entry.backend_etag {
+            backend_headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = entry.last_modified() {
+            backend_headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+    let breaker = breaker.zip(breaker_key(&url_backend));
+    if let Some(((_, cooldown), backend)) = &breaker {
+        if !state.breakers.allow(backend, *cooldown) {
+            debug!("circuit breaker of the backend service is open, it is not requested");
+            if let Some((uuid, entry)) = stale.filter(|(_, entry)| entry.can_serve_stale()) {
+                cache_event("stale", Some(&uuid), &req_method, &req_uri, &req_host);
+                let rep = stale_response(entry, &req_headers);
+                state.metrics.hit(&rep);
+                return with_cache_control(rep, &client_cache_control);
+            }
+            return service_unavailable(retry_after);
+        }
+    }
+    Metrics::inc(&state.metrics.backend_requests);
+    let req = send_backend(
+        &state.client,
+        req_method.clone(),
+        url_backend,
+        backend_headers,
+        request.into_body(),
+        retry,
+    )
+    .await;
+    if let Some(((threshold, _), backend)) = &breaker {
+        match &req {
+            Ok(_) => state.breakers.success(backend),
+            Err(_) => state.breakers.failure(backend, *threshold),
+        }
+    }
     match req {
-        Ok(mut rep) => {
+        Ok(rep) => {
+            if passthrough {
+                return stream_response(rep);
+            }
+            if rep.status().is_server_error() {
+                if let Some((uuid, entry)) = stale.take_if(|(_, entry)| entry.serve_if_error()) {
+                    warn!("the backend service answered with an error, serving the stale entry");
+                    cache_event("stale", Some(&uuid), &req_method, &req_uri, &req_host);
+                    let rep = stale_response(entry, &req_headers);
+                    state.metrics.hit(&rep);
+                    return with_cache_control(rep, &client_cache_control);
+                }
+            }
+            if let Some((uuid, mut entry)) = stale {
+                if rep.status() == StatusCode::NOT_MODIFIED {
+                    // the stale entry is still valid, no need to download the body again.
+                    debug!("backend service revalidated the stale entry");
+                    entry.refresh(rep.headers());
+                    state.cache.insert(uuid, entry.clone()).await;
+                    cache_event("hit", Some(&uuid), &req_method, &req_uri, &req_host);
+                    let rep = serve_entry(entry, &req_headers);
+                    state.metrics.hit(&rep);
+                    return with_cache_control(rep, &client_cache_control);
+                }
+                // the backend service sent a new response, the stale entry is replaced.
+                state.cache.invalidate(&uuid).await;
+                index.delete_uuid_from_index(&uuid);
+            }
+            if rep.status() == StatusCode::NOT_MODIFIED {
+                // answer to the conditional headers of the client, there is no entry to refresh and no body to store.
+                debug!("backend service answered 304 without a cache entry, forwarding it");
+                let rep = cache_miss(stream_response(rep));
+                return with_cache_control(rep, &client_cache_control);
+            }
+            let status = rep.status();
+            let mut headers = rep.headers().to_owned();
+            strip_hop_by_hop(&mut headers);
+            let limit = cache_config.max_cacheable_body_bytes as usize;
+            let body = match buffer_body(rep, limit, backend_idle_timeout).await {
+                Ok(BackendBody::Buffered(body)) => match &rewrite {
+                    Some(rewrite) => rewrite.apply(&mut headers, body),
+                    None => body,
+                },
+                Ok(BackendBody::Streamed(body)) => {
+                    debug!("response body is too large to be cached, streaming it to the client");
+                    let rep = cache_miss((status, headers, body).into_response());
+                    return with_cache_control(rep, &client_cache_control);
+                }
+                Err(BodyError::Stalled) => {
+                    Metrics::inc(&state.metrics.backend_errors);
+                    warn!("the backend service stalled while sending the body, nothing is cached");
+                    return StatusCode::GATEWAY_TIMEOUT.into_response();
+                }
+                Err(BodyError::Read(err)) => {
+                    Metrics::inc(&state.metrics.backend_errors);
+                    warn!("the body of the backend service response could not be read");
+                    debug!("{err}");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            };
+            // a rewrite can make the body larger than the limit.
+            if body.len() > limit {
+                debug!("rewritten response body is too large to be cached");
+                let rep = cache_miss((status, headers, body).into_response());
+                return with_cache_control(rep, &client_cache_control);
+            }
+            // the backend service forbids storing the response, its status is not cacheable (transient errors), it varies on anything
+            // or it sets a cookie for a single user, it is served without Etag.
+            let cache_control = CacheControl::from_headers(&headers);
+            if cache_control.no_store
+                || cache_control.private
+                || !cache_config.is_cacheable_status(status)
+                || status == StatusCode::PARTIAL_CONTENT
+                || vary_any(headers.get_all(VARY))
+                || (headers.contains_key(SET_COOKIE) && !cache_config.cache_set_cookie)
+            {
+                debug!("response is not storable, it is not cached");
+                let rep = cache_miss((status, headers, body).into_response());
+                return with_cache_control(rep, &client_cache_control);
+            }
             // first send Response and then cache so client wait as little as possible.
-            // need to add Etag headers to response
+            // need to add Etag headers to response, unless the backend service gave its own.
             let uuid = Uuid::new_v4();
             let cache = state.cache.clone();
-            rep.headers_mut()
-                .insert(ETAG, HeaderValue::from_str(&uuid.to_string()).unwrap());
-            let headers = rep.headers().to_owned();
-            let req_headers_match_vary = match headers_match_vary(&req_headers, headers.get(VARY)) {
-                Ok(h) => h,
-                Err(err) => {
-                    warn!("backend service contains malformated header value for Vary");
-                    debug!("{err}");
-                    trace!("{:?}", rep);
-                    HeaderMap::new()
+            let backend_etag = add_etag(&mut headers, &uuid);
+            let mut req_headers_match_vary =
+                match headers_match_vary(&req_headers, headers.get_all(VARY)) {
+                    Ok(h) => h,
+                    Err(err) => {
+                        warn!("backend service contains malformated header value for Vary");
+                        debug!("{err}");
+                        trace!("{:?}", headers);
+                        HeaderMap::new()
+                    }
+                };
+            // headers configured to always be part of the cache key
+            for name in always_vary_on {
+                if let Some(value) = req_headers.get(&name) {
+                    req_headers_match_vary.insert(name, value.clone());
                 }
-            };
+            }
 
-            let axum_rep = (
-                rep.status(),
-                rep.headers().to_owned(),
-                rep.bytes().await.unwrap(),
-            );
+            let axum_rep = (status, headers, body);
 
-            spawn(enc!((uuid, axum_rep, index) async move {
+            let mut entry = CacheEntry::new(
+                axum_rep.0,
+                axum_rep.1.clone(),
+                axum_rep.2.clone(),
+                backend_etag,
+                ttl_override.or_else(|| freshness_lifetime(&axum_rep.1, &cache_config)),
+            );
+            entry.expiration = Some(Duration::from_secs(cache_config.expiration));
+            entry.stale_if_error = cache_config.stale_if_error();
+            let range = req_headers.contains_key(RANGE).then(|| entry.clone());
+            let max_variants = cache_config.max_variants_per_key;
+            let tags = surrogate_keys(&axum_rep.1);
+            state.tasks.spawn(enc!((uuid, index) async move {
                 if let Some(host) = req_host {
                 // add entry to index cache
                 debug!("adding the new response to the cache and indexing");
-                index.lock().await.add_entry(uuid, req_method, req_uri, host, req_headers_match_vary);
+                let removed = index.add_entry(uuid, req_method, key_uri, host, req_headers_match_vary, max_variants);
+                // the replaced or evicted responses are now unreachable
+                for removed in removed {
+                    cache.invalidate(&removed).await;
+                }
+                index.tag_entry(uuid, tags);
                 // add response to cache
-                cache.insert(uuid, axum_rep).await;
+                cache.insert(uuid, entry).await;
                 } else {
                     warn!("request does not have a HOST header, not adding any entry to cache");
                 }
-
+                // the identical requests waiting for this response can now find it in the cache.
+                drop(fetch);
             }));
             debug!("serving new response with added header Etag");
             trace!("{:?}", axum_rep);
-            axum_rep.into_response()
+            let rep = match range {
+                Some(entry) => with_range(entry, &req_headers),
+                None => axum_rep.into_response(),
+            };
+            with_cache_control(cache_miss(rep), &client_cache_control)
         }
         Err(err) => {
             // the request to the backend failed
+            Metrics::inc(&state.metrics.backend_errors);
             warn!("the request to the backend service failed");
             debug!("{}", err);
+            if let Some((uuid, entry)) = stale.filter(|(_, entry)| entry.serve_if_error()) {
+                debug!("serving the stale entry instead of the error");
+                cache_event("stale", Some(&uuid), &req_method, &req_uri, &req_host);
+                let rep = stale_response(entry, &req_headers);
+                state.metrics.hit(&rep);
+                return with_cache_control(rep, &client_cache_control);
+            }
             StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
+
+/// response served from a cache entry.
+/// X-Cache-Date gives the date the entry was last fetched from the backend service and Age the seconds since then, added to the Age of the backend service.
+/// Date is the date of the fetch if the backend service did not give one.
+fn serve_entry(entry: CacheEntry, req_headers: &HeaderMap) -> Response {
+    let fetched_at = HeaderValue::from_str(&fmt_http_date(entry.fetched_at)).unwrap();
+    // the age given by the backend service, if it is a cache itself, plus the time spent in this cache.
+    let backend_age = entry
+        .headers
+        .get(AGE)
+        .and_then(|age| age.to_str().ok()?.trim().parse::<u64>().ok())
+        .unwrap_or_default();
+    let age = backend_age.saturating_add(entry.age().as_secs());
+    let date = (!entry.headers.contains_key(DATE)).then(|| fetched_at.clone());
+    let mut rep = with_range(entry, req_headers);
+    rep.headers_mut().insert(X_CACHE_DATE, fetched_at);
+    if let Some(date) = date {
+        rep.headers_mut().insert(DATE, date);
+    }
+    rep.headers_mut().insert(AGE, HeaderValue::from(age));
+    rep.headers_mut()
+        .insert(X_CACHE, HeaderValue::from_static("HIT"));
+    rep
+}
+
+/// full entry, or the byte range requested if the entry is a 200 response.
+/// A full 200 response advertises Accept-Ranges and a single byte range of it can be requested, unless the backend service sent `Accept-Ranges: none`.
+/// With If-Range, the range is only served if the validator is the one of the entry, the full entry otherwise.
+fn with_range(entry: CacheEntry, req_headers: &HeaderMap) -> Response {
+    let refused = entry
+        .headers
+        .get(ACCEPT_RANGES)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("none"));
+    let ranges = entry.status == StatusCode::OK && !refused;
+    let range = req_headers
+        .get(RANGE)
+        .filter(|_| ranges && check_if_range(req_headers, &entry));
+    let mut rep = match range {
+        Some(range) => serve_range(entry, range),
+        None => entry.into_response(),
+    };
+    if ranges {
+        rep.headers_mut()
+            .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+    rep
+}
+
+/// response fetched from the backend service for a cacheable request.
+fn cache_miss(mut rep: Response) -> Response {
+    rep.headers_mut()
+        .insert(X_CACHE, HeaderValue::from_static("MISS"));
+    rep
+}
+
+/// remove the headers only meaningful for a single connection, they are not forwarded by a proxy (RFC 7230 section 6.1).
+/// Headers listed in the Connection header are removed too.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in [
+        CONNECTION,
+        KEEP_ALIVE,
+        PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION,
+        TE,
+        TRAILER,
+        TRANSFER_ENCODING,
+        UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+}
+
+/// protocol of the request, for X-Forwarded-Proto.
+/// HTTPS is only served on the listen address, to requests coming through a listener.
+fn forwarded_proto(config: &Config, listener: &Option<Extension<Listener>>) -> &'static str {
+    match (&config.tls, listener) {
+        (Some(_), Some(l)) if l.address == config.listen_address => "https",
+        _ => "http",
+    }
+}
+
+/// tell the backend service the client address, the HOST and the protocol of the original request.
+/// The client address is appended to an existing X-Forwarded-For chain.
+/// X-Forwarded-Host and X-Forwarded-Proto are always replaced, they are not part of the cache key and a client could otherwise change the cached responses for everyone.
+fn forwarding_headers(
+    headers: &mut HeaderMap,
+    client: Option<IpAddr>,
+    host: &Option<HeaderValue>,
+    proto: &'static str,
+) {
+    if let Some(client) = client {
+        let chain = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .chain([client.to_string().as_str()])
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(chain) = HeaderValue::from_str(&chain) {
+            headers.insert(X_FORWARDED_FOR, chain);
+        }
+    }
+    match host {
+        Some(host) => headers.insert(X_FORWARDED_HOST, host.clone()),
+        None => headers.remove(X_FORWARDED_HOST),
+    };
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+}
+
+/// add the uuid of the entry as ETag if the backend service did not give one.
+/// Will return the ETag of the backend service, kept to revalidate the entry.
+fn add_etag(headers: &mut HeaderMap, uuid: &Uuid) -> Option<HeaderValue> {
+    let backend_etag = headers.get(ETAG).cloned();
+    if backend_etag.is_none() {
+        headers.insert(ETAG, HeaderValue::from_str(&uuid.to_string()).unwrap());
+    }
+    backend_etag
+}
+
+/// stale entry served because the backend service could not give a new response, with a Warning header telling so.
+fn stale_response(entry: CacheEntry, req_headers: &HeaderMap) -> Response {
+    let mut rep = serve_entry(entry, req_headers);
+    rep.headers_mut().insert(
+        WARNING,
+        HeaderValue::from_static("111 - \"Revalidation Failed\""),
+    );
+    rep
+}
+
+/// marks the request of a background revalidation, the stale entry must not be served to it.
+#[derive(Clone)]
+struct Revalidation;
+
+/// revalidate a stale entry with the backend service in the background, as a GET request identical to the one of the client.
+/// The conditional and range headers of the client are removed, the entry is updated for every client.
+/// The guard is kept until the revalidation is done, so the entry is revalidated once at a time.
+fn spawn_revalidation(
+    state: &AppState,
+    listener: Option<Extension<Listener>>,
+    request: &Request,
+    guard: FetchGuard,
+) {
+    let mut revalidation = Request::new(Body::empty());
+    *revalidation.uri_mut() = request.uri().clone();
+    *revalidation.headers_mut() = request.headers().clone();
+    for name in [IF_NONE_MATCH, IF_MODIFIED_SINCE, RANGE, X_CACHE_TTL] {
+        revalidation.headers_mut().remove(name);
+    }
+    revalidation.extensions_mut().insert(Revalidation);
+    state.tasks.spawn(enc!((state) async move {
+        let _ = handler(State(state), None, listener, revalidation).await;
+        drop(guard);
+    }));
+}
+
+/// send a request to the backend service.
+/// The body of the request is streamed, large uploads are not held in memory.
+/// Idempotent requests without body are retried when the connection fails, a body can not be sent twice.
+async fn send_backend(
+    client: &Client,
+    method: Method,
+    url: Url,
+    headers: HeaderMap,
+    body: Body,
+    retry: RetryPolicy,
+) -> reqwest::Result<reqwest::Response> {
+    let retryable = is_idempotent(&method) && HttpBody::size_hint(&body).exact() == Some(0);
+    if !retryable || retry.max_attempts == 1 {
+        return client
+            .request(method, url)
+            .headers(headers)
+            .body(reqwest::Body::wrap_stream(body.into_data_stream()))
+            .send()
+            .await;
+    }
+    let mut attempt = 1;
+    loop {
+        let req = client
+            .request(method.clone(), url.clone())
+            .headers(headers.clone())
+            .send()
+            .await;
+        match req {
+            Err(err) if err.is_connect() && attempt < retry.max_attempts => {
+                let delay = retry.delay(attempt);
+                debug!("could not connect to the backend service, retrying in {delay:?} (attempt {attempt})");
+                trace!("{err}");
+                sleep(delay).await;
+                attempt += 1;
+            }
+            req => return req,
+        }
+    }
+}
+
+/// methods whose requests can be repeated without changing the result.
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// headers of a request telling apart the responses of its signature, with their values:
+/// those of always_vary_on and those the entries already cached for the signature vary on.
+fn variant_headers(
+    index: &IndexCache,
+    signature: &Option<Signature>,
+    always_vary_on: &[HeaderName],
+    headers: &HeaderMap,
+) -> Vec<(HeaderName, HeaderValue)> {
+    let mut names = always_vary_on.to_vec();
+    if let Some(signature) = signature {
+        names.extend(index.vary_names(signature));
+    }
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    names
+        .into_iter()
+        .filter_map(|name| Some((name.clone(), headers.get(&name)?.clone())))
+        .collect()
+}
+
+/// response to a HEAD request from the response to a GET request.
+/// The Content-Length of the body is kept.
+fn without_body(rep: Response) -> Response {
+    let (mut parts, body) = rep.into_parts();
+    if let Some(len) = HttpBody::size_hint(&body).exact() {
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
+/// 206 response with the requested byte range of the entry, 416 if the range can not be satisfied.
+/// Ranges that can not be parsed or multiple ranges are ignored, the full entry is served.
+fn serve_range(entry: CacheEntry, range: &HeaderValue) -> Response {
+    let len = entry.body.len();
+    match parse_range(range, len) {
+        Some(Some((start, end))) => {
+            let mut headers = entry.headers;
+            headers.remove(CONTENT_LENGTH);
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {start}-{end}/{len}")).unwrap(),
+            );
+            let body = entry.body.slice(start..=end);
+            (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+        }
+        Some(None) => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(CONTENT_RANGE, format!("bytes */{len}"))],
+        )
+            .into_response(),
+        None => entry.into_response(),
+    }
+}
+
+/// true if the request asks for several byte ranges, they are passed through to the backend service.
+fn is_multi_range(headers: &HeaderMap) -> bool {
+    headers
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok())
+        .is_some_and(|range| range.contains(','))
+}
+
+/// parse a single byte range for a body of len bytes, into the inclusive positions of its first and last byte.
+/// None if the range is not a single valid byte range, like one ending before its start, Some(None) if it can not be satisfied.
+fn parse_range(range: &HeaderValue, len: usize) -> Option<Option<(usize, usize)>> {
+    let range = range.to_str().ok()?.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // last bytes of the body
+        let suffix = end.parse::<usize>().ok()?;
+        (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+    } else {
+        let start = start.parse::<usize>().ok()?;
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            let end = end.parse::<usize>().ok()?;
+            // the last position before the first one makes the range invalid, it is ignored.
+            if end < start {
+                return None;
+            }
+            end.min(len.saturating_sub(1))
+        };
+        (start < len).then_some((start, end))
+    };
+    Some(range)
+}
+
+/// replace the Cache-Control of a response sent to the client, if the endpoint overrides it.
+/// The cache entry keeps the Cache-Control of the backend service.
+fn with_cache_control(mut rep: Response, cache_control: &Option<HeaderValue>) -> Response {
+    if let Some(cache_control) = cache_control {
+        rep.headers_mut()
+            .insert(CACHE_CONTROL, cache_control.clone());
+    }
+    rep
+}
+
+/// 503 response, with a Retry-After header so well-behaved clients back off.
+/// retry_after is in seconds, 0 omits the header.
+pub fn service_unavailable(retry_after: u64) -> Response {
+    if retry_after == 0 {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, retry_after.to_string())],
+    )
+        .into_response()
+}
+
+/// gRPC-web calls are never cached, their trailers are sent at the end of the body so it must be streamed as is.
+#[cfg(feature = "grpc-web")]
+fn is_grpc_web(headers: &HeaderMap) -> bool {
+    headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .is_some_and(|c| c.starts_with("application/grpc-web"))
+}
+#[cfg(not(feature = "grpc-web"))]
+fn is_grpc_web(_headers: &HeaderMap) -> bool {
+    false
+}
+
+/// response of the backend service streamed to the client as is, without caching it.
+fn stream_response(rep: reqwest::Response) -> Response {
+    let status = rep.status();
+    let mut headers = rep.headers().to_owned();
+    strip_hop_by_hop(&mut headers);
+    (status, headers, Body::from_stream(rep.bytes_stream())).into_response()
+}
+
+/// body of a backend service response.
+enum BackendBody {
+    /// fully read, it can be cached.
+    Buffered(Bytes),
+    /// too large to be cached, streamed to the client.
+    Streamed(Body),
+}
+
+/// error while buffering the body of a backend service response.
+enum BodyError {
+    /// the backend service sent nothing for longer than the idle timeout.
+    Stalled,
+    Read(reqwest::Error),
+}
+
+/// buffer the body of a backend service response up to limit bytes.
+/// Bodies without Content-Length are read chunk by chunk, if the limit is exceeded the buffering is aborted and the rest of the body is streamed after what was already read.
+/// Waiting for a chunk longer than idle aborts the buffering, none to wait indefinitely.
+async fn buffer_body(
+    rep: reqwest::Response,
+    limit: usize,
+    idle: Option<Duration>,
+) -> Result<BackendBody, BodyError> {
+    if rep.content_length().is_some_and(|len| len as usize > limit) {
+        return Ok(BackendBody::Streamed(Body::from_stream(rep.bytes_stream())));
+    }
+    let mut buffer = Vec::new();
+    let mut stream = rep.bytes_stream();
+    loop {
+        let next = match idle {
+            Some(idle) => timeout(idle, stream.next())
+                .await
+                .map_err(|_| BodyError::Stalled)?,
+            None => stream.next().await,
+        };
+        let Some(chunk) = next else {
+            break;
+        };
+        let chunk = chunk.map_err(BodyError::Read)?;
+        if buffer.len() + chunk.len() > limit {
+            let read = stream::iter([Ok(Bytes::from(buffer)), Ok(chunk)]);
+            return Ok(BackendBody::Streamed(Body::from_stream(read.chain(stream))));
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(BackendBody::Buffered(Bytes::from(buffer)))
+}
+
+/// structured event about the use of the cache for a request, so log aggregators can filter and count them.
+/// result is one of:
+/// - hit, served from a fresh entry or one revalidated by the backend service, with a 304 too if the validators of the client match.
+/// - stale, served from a stale entry while it is revalidated in the background.
+/// - miss, fetched from the backend service to be cached.
+/// - bypass, passed through to the backend service without the cache.
+fn cache_event(
+    result: &str,
+    uuid: Option<&Uuid>,
+    method: &Method,
+    path: &PathAndQuery,
+    host: &Option<HeaderValue>,
+) {
+    info!(
+        cache.result = result,
+        cache.uuid = uuid.map(display),
+        http.method = %method,
+        http.path = %path,
+        http.host = host.as_ref().and_then(|h| h.to_str().ok()),
+        "cache {result}"
+    );
+}
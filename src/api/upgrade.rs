@@ -0,0 +1,127 @@
+use axum::extract::Request;
+use axum::http::{uri::PathAndQuery, HeaderMap, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use reqwest::header::{CONNECTION, HOST, UPGRADE};
+use reqwest::StatusCode;
+use std::net::IpAddr;
+use tracing::{debug, warn};
+
+use crate::api::metrics::Metrics;
+use crate::config::Listener;
+use crate::AppState;
+
+use super::{
+    forwarded_proto, forwarding_headers, service_unavailable, stream_response, strip_hop_by_hop,
+};
+
+/// true if the request asks to switch to another protocol on the same connection, like WebSocket.
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(UPGRADE)
+        && headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// relay a protocol upgrade to the backend service, the cache is not used.
+/// Once the backend service accepted it with a 101 response, the bytes of both connections are copied both ways until one of them closes.
+pub async fn proxy_upgrade(
+    state: &AppState,
+    client: Option<IpAddr>,
+    listener: &Option<Extension<Listener>>,
+    mut request: Request,
+) -> Response {
+    let Some(on_upgrade) = request.extensions_mut().remove::<OnUpgrade>() else {
+        debug!("the connection of the request can not be upgraded");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let req_host = request.headers().get(HOST).cloned();
+    let req_uri = request
+        .uri()
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
+    let (url_backend, backend_host, proto) = {
+        let config = state.config.read().await;
+        if config.maintenance {
+            debug!("maintenance mode, the upgrade is not relayed");
+            return service_unavailable(config.retry_after);
+        }
+        let url_backend = config.to_backend_uri(&req_uri, &req_host);
+        if config.is_self_referential(&url_backend) {
+            warn!("the backend url points to Mnemosyne itself, refusing to loop");
+            return StatusCode::LOOP_DETECTED.into_response();
+        }
+        let backend_host = config.backend_host(&req_host, &req_uri, &url_backend);
+        (
+            url_backend,
+            backend_host,
+            forwarded_proto(&config, listener),
+        )
+    };
+    // the headers asking for the upgrade are the only hop-by-hop headers forwarded.
+    let mut headers = request.headers().clone();
+    let upgrade = headers.get(UPGRADE).cloned();
+    strip_hop_by_hop(&mut headers);
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    if let Some(upgrade) = upgrade {
+        headers.insert(UPGRADE, upgrade);
+    }
+    if let Some(host) = backend_host {
+        headers.insert(HOST, host);
+    }
+    forwarding_headers(&mut headers, client, &req_host, proto);
+    debug!("relaying the upgrade of the connection to {url_backend}");
+    Metrics::inc(&state.metrics.backend_requests);
+    let rep = match state
+        .client
+        .request(request.method().clone(), url_backend)
+        .headers(headers)
+        .send()
+        .await
+    {
+        Ok(rep) => rep,
+        Err(err) => {
+            Metrics::inc(&state.metrics.backend_errors);
+            warn!("the request to the backend service failed");
+            debug!("{err}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if rep.status() != StatusCode::SWITCHING_PROTOCOLS {
+        debug!("the backend service refused the upgrade");
+        return stream_response(rep);
+    }
+    // the response keeps its Connection and Upgrade headers, they complete the handshake.
+    let response = (rep.status(), rep.headers().clone()).into_response();
+    // tracked with the other tasks, the relayed connections are closed before Mnemosyne exits.
+    let stopping = state.stopping.clone();
+    state.tasks.spawn(async move {
+        let (mut backend, client) = match tokio::try_join!(
+            async { rep.upgrade().await.map_err(|e| e.to_string()) },
+            async { on_upgrade.await.map_err(|e| e.to_string()) }
+        ) {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                warn!("the upgraded connection could not be established");
+                debug!("{err}");
+                return;
+            }
+        };
+        let mut client = TokioIo::new(client);
+        tokio::select! {
+            relayed = tokio::io::copy_bidirectional(&mut client, &mut backend) => {
+                if let Err(err) = relayed {
+                    debug!("upgraded connection closed: {err}");
+                }
+            }
+            _ = stopping.cancelled() => debug!("Mnemosyne is stopping, closing the upgraded connection"),
+        }
+    });
+    response
+}
@@ -0,0 +1,151 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::body::Bytes;
+use axum::http::uri::PathAndQuery;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use uuid::Uuid;
+
+/// start of an archive, to refuse files that are not archives of Mnemosyne.
+/// The second version adds the validator and freshness of the entries, so they keep their age once loaded.
+pub const MAGIC: &[u8] = b"MNEMOSYNE-ARCHIVE-2";
+
+/// cache entry with its signature, as stored in an archive.
+/// Every field is written in order, variable length fields are prefixed by their length as a big endian u32.
+pub struct ArchivedEntry {
+    pub uuid: Uuid,
+    pub method: Method,
+    pub path: PathAndQuery,
+    pub host: HeaderValue,
+    /// request headers matching the Vary header of the response.
+    pub vary: HeaderMap,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// ETag sent by the backend service, empty if none.
+    pub backend_etag: Option<HeaderValue>,
+    /// written in milliseconds since the unix epoch.
+    pub fetched_at: SystemTime,
+    /// written in milliseconds, u64::MAX if none.
+    pub freshness: Option<Duration>,
+}
+
+impl ArchivedEntry {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = Vec::with_capacity(self.body.len() + 256);
+        buf.extend_from_slice(self.uuid.as_bytes());
+        put_bytes(&mut buf, self.method.as_str().as_bytes());
+        put_bytes(&mut buf, self.path.as_str().as_bytes());
+        put_bytes(&mut buf, self.host.as_bytes());
+        put_headers(&mut buf, &self.vary);
+        buf.extend_from_slice(&self.status.as_u16().to_be_bytes());
+        put_headers(&mut buf, &self.headers);
+        put_bytes(&mut buf, &self.body);
+        put_bytes(
+            &mut buf,
+            self.backend_etag
+                .as_ref()
+                .map_or(&[], |etag| etag.as_bytes()),
+        );
+        let fetched_at = self
+            .fetched_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        buf.extend_from_slice(&fetched_at.to_be_bytes());
+        let freshness = self
+            .freshness
+            .map_or(u64::MAX, |freshness| freshness.as_millis() as u64);
+        buf.extend_from_slice(&freshness.to_be_bytes());
+        buf.into()
+    }
+    /// read every entry of an archive.
+    /// None if the archive is malformed, no entry should be imported in this case.
+    pub fn decode_all(mut archive: Bytes) -> Option<Vec<Self>> {
+        if !archive.starts_with(MAGIC) {
+            return None;
+        }
+        let _ = archive.split_to(MAGIC.len());
+        let mut entries = vec![];
+        while !archive.is_empty() {
+            entries.push(Self::decode(&mut archive)?);
+        }
+        Some(entries)
+    }
+    fn decode(buf: &mut Bytes) -> Option<Self> {
+        let uuid = Uuid::from_slice(&take(buf, 16)?).ok()?;
+        let method = Method::from_bytes(&take_bytes(buf)?).ok()?;
+        let path = PathAndQuery::from_maybe_shared(take_bytes(buf)?).ok()?;
+        let host = HeaderValue::from_maybe_shared(take_bytes(buf)?).ok()?;
+        let vary = take_headers(buf)?;
+        let status = take(buf, 2)?;
+        let status = StatusCode::from_u16(u16::from_be_bytes([status[0], status[1]])).ok()?;
+        let headers = take_headers(buf)?;
+        let body = take_bytes(buf)?;
+        let backend_etag = take_bytes(buf)?;
+        let backend_etag = if backend_etag.is_empty() {
+            None
+        } else {
+            Some(HeaderValue::from_maybe_shared(backend_etag).ok()?)
+        };
+        let fetched_at = UNIX_EPOCH + Duration::from_millis(take_u64(buf)?);
+        let freshness = Some(take_u64(buf)?)
+            .filter(|freshness| *freshness != u64::MAX)
+            .map(Duration::from_millis);
+        Some(Self {
+            uuid,
+            method,
+            path,
+            host,
+            vary,
+            status,
+            headers,
+            body,
+            backend_etag,
+            fetched_at,
+            freshness,
+        })
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_headers(buf: &mut Vec<u8>, headers: &HeaderMap) {
+    buf.extend_from_slice(&(headers.len() as u32).to_be_bytes());
+    for (name, value) in headers {
+        put_bytes(buf, name.as_str().as_bytes());
+        put_bytes(buf, value.as_bytes());
+    }
+}
+
+fn take(buf: &mut Bytes, len: usize) -> Option<Bytes> {
+    (buf.len() >= len).then(|| buf.split_to(len))
+}
+
+fn take_u32(buf: &mut Bytes) -> Option<usize> {
+    let len = take(buf, 4)?;
+    Some(u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+}
+
+fn take_u64(buf: &mut Bytes) -> Option<u64> {
+    let n = take(buf, 8)?;
+    Some(u64::from_be_bytes(n[..].try_into().ok()?))
+}
+
+fn take_bytes(buf: &mut Bytes) -> Option<Bytes> {
+    let len = take_u32(buf)?;
+    take(buf, len)
+}
+
+fn take_headers(buf: &mut Bytes) -> Option<HeaderMap> {
+    let count = take_u32(buf)?;
+    let mut headers = HeaderMap::new();
+    for _ in 0..count {
+        let name = HeaderName::from_bytes(&take_bytes(buf)?).ok()?;
+        let value = HeaderValue::from_maybe_shared(take_bytes(buf)?).ok()?;
+        headers.append(name, value);
+    }
+    Some(headers)
+}
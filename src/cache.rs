@@ -1,51 +1,450 @@
+use std::io::{Read, Write};
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use axum::body::Bytes;
+use axum::response::{IntoResponse, Response};
 use derive_more::{Deref, DerefMut};
+use httpdate::parse_http_date;
 use moka::future::Cache as MokaCache;
-use reqwest::header::{HeaderMap, ETAG};
+use moka::notification::RemovalCause;
+use moka::Expiry;
+use reqwest::header::{
+    HeaderMap, HeaderValue, AGE, CONTENT_ENCODING, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED,
+};
 use reqwest::StatusCode;
+use tracing::warn;
 use typesize::TypeSize;
 use uuid::Uuid;
 
-use crate::config::Config;
+use crate::cache_control::CacheControl;
+use crate::config::{CacheConfig, Config};
+use crate::index_cache::IndexCache;
 #[derive(Deref, DerefMut, Clone, Debug)]
-pub struct Cache(pub MokaCache<Uuid, (StatusCode, HeaderMap, Bytes), ahash::RandomState>);
+pub struct Cache(
+    #[deref]
+    #[deref_mut]
+    pub MokaCache<Uuid, CacheEntry, ahash::RandomState>,
+    /// bodies larger than this size in bytes are stored compressed, none to store them as is.
+    Option<usize>,
+);
+
+/// a response of a backend service stored in the cache.
+#[derive(Clone, Debug)]
+pub struct CacheEntry {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// ETag sent by the backend service, used to revalidate the entry once stale.
+    pub backend_etag: Option<HeaderValue>,
+    /// when the response was fetched (or last revalidated) from the backend service.
+    pub fetched_at: SystemTime,
+    /// the body is stored compressed with brotli, it is decompressed when read from the cache.
+    pub compressed: bool,
+    /// how long the entry stays fresh after being fetched.
+    /// None if the backend service does not give any freshness information, the entry will then only be expired by the cache.
+    pub freshness: Option<Duration>,
+    /// expiration after the last request for this entry, replacing the one of the cache.
+    pub expiration: Option<Duration>,
+    /// time after the freshness lifetime during which the stale entry is served while it is revalidated in the background.
+    /// From the stale-while-revalidate directive of the backend service.
+    pub stale_while_revalidate: Option<Duration>,
+    /// time after the freshness lifetime during which the stale entry is served if the backend service fails.
+    pub stale_if_error: Option<Duration>,
+    /// the backend service sent must-revalidate, proxy-revalidate or no-cache, the entry is never served stale.
+    pub must_revalidate: bool,
+}
+
+impl CacheEntry {
+    pub fn new(
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        backend_etag: Option<HeaderValue>,
+        freshness: Option<Duration>,
+    ) -> Self {
+        let cache_control = CacheControl::from_headers(&headers);
+        let stale_while_revalidate = cache_control
+            .stale_while_revalidate
+            .map(Duration::from_secs);
+        Self {
+            status,
+            headers,
+            body,
+            backend_etag,
+            fetched_at: SystemTime::now(),
+            compressed: false,
+            freshness,
+            expiration: None,
+            stale_while_revalidate,
+            stale_if_error: None,
+            must_revalidate: cache_control.must_revalidate || cache_control.no_cache,
+        }
+    }
+    /// time elapsed since the entry was fetched from the backend service.
+    pub fn age(&self) -> Duration {
+        self.fetched_at.elapsed().unwrap_or_default()
+    }
+    /// an entry is stale once its age reached its freshness lifetime.
+    /// max-age=0 makes an entry stale as soon as it is stored.
+    pub fn is_stale(&self) -> bool {
+        self.freshness
+            .is_some_and(|freshness| self.age() >= freshness)
+    }
+    /// false if the backend service forbids serving the entry once stale without revalidating it.
+    pub fn can_serve_stale(&self) -> bool {
+        !self.must_revalidate
+    }
+    /// true if the entry is stale but still within its stale-while-revalidate window.
+    pub fn serve_while_revalidate(&self) -> bool {
+        if !self.can_serve_stale() {
+            return false;
+        }
+        match (self.freshness, self.stale_while_revalidate) {
+            (Some(freshness), Some(window)) => self.is_stale() && self.age() < freshness + window,
+            _ => false,
+        }
+    }
+    /// true if the entry can be served instead of an error of the backend service.
+    pub fn serve_if_error(&self) -> bool {
+        self.can_serve_stale()
+            && self
+                .stale_if_error
+                .is_some_and(|window| self.age() < self.freshness.unwrap_or_default() + window)
+    }
+    /// time after the freshness lifetime the entry is still useful, to be served stale.
+    fn stale_window(&self) -> Duration {
+        if !self.can_serve_stale() {
+            return Duration::ZERO;
+        }
+        self.stale_while_revalidate
+            .unwrap_or_default()
+            .max(self.stale_if_error.unwrap_or_default())
+    }
+    /// true if the backend service gave a validator, a stale entry can then be revalidated with a conditional request.
+    pub fn can_revalidate(&self) -> bool {
+        self.backend_etag.is_some() || self.last_modified().is_some()
+    }
+    /// size in bytes of the entry as it is stored in memory.
+    /// The body is measured in its stored representation, so the size limit of the cache reflects the real memory footprint.
+    pub fn stored_size(&self) -> u32 {
+        let s = self.status.to_string().get_size() as u32;
+        let h = self.headers.iter().fold(0, |acc, (name, value)| {
+            acc + (name.as_str().to_string().get_size() + value.as_bytes().to_vec().get_size())
+                as u32
+        });
+        let b = self.body.len() as u32;
+        s + h + b
+    }
+    /// the entry with its body decompressed, as read from the cache.
+    /// None if the body can not be decompressed.
+    pub fn decompressed(mut self) -> Option<Self> {
+        if self.compressed {
+            let Some(body) = decompress(&self.body) else {
+                warn!("the body of a cache entry could not be decompressed, entry ignored");
+                return None;
+            };
+            self.body = body;
+            self.compressed = false;
+        }
+        Some(self)
+    }
+    /// Last-Modified header sent by the backend service, used with the backend ETag to revalidate the entry once stale.
+    pub fn last_modified(&self) -> Option<&HeaderValue> {
+        self.headers.get(LAST_MODIFIED)
+    }
+    /// the backend service confirmed the entry is still valid with a 304 response.
+    /// freshness is updated from the headers of the 304 response if present.
+    pub fn refresh(&mut self, headers: &HeaderMap) {
+        self.fetched_at = SystemTime::now();
+        match headers.get(AGE) {
+            Some(age) => self.headers.insert(AGE, age.clone()),
+            None => self.headers.remove(AGE),
+        };
+        if let Some(freshness) = explicit_freshness(headers) {
+            self.freshness = Some(freshness);
+        }
+        if let Some(window) = CacheControl::from_headers(headers).stale_while_revalidate {
+            self.stale_while_revalidate = Some(Duration::from_secs(window));
+        }
+    }
+}
+
+impl IntoResponse for CacheEntry {
+    fn into_response(self) -> Response {
+        (self.status, self.headers, self.body).into_response()
+    }
+}
+
+/// freshness lifetime of a response.
+/// The freshness given by the backend service takes precedence, then the heuristic from Last-Modified if enabled, then the default ttl if configured.
+pub fn freshness_lifetime(headers: &HeaderMap, config: &CacheConfig) -> Option<Duration> {
+    explicit_freshness(headers)
+        .or_else(|| {
+            config
+                .heuristic_freshness
+                .then(|| heuristic_freshness(headers, config.heuristic_max_ttl_secs))
+                .flatten()
+        })
+        .or_else(|| {
+            (config.default_ttl_secs > 0).then(|| Duration::from_secs(config.default_ttl_secs))
+        })
+}
+
+/// heuristic freshness lifetime (RFC 7234 4.2.2), 10% of the time elapsed since the last modification of the resource.
+/// clamped to max_ttl seconds.
+fn heuristic_freshness(headers: &HeaderMap, max_ttl: u64) -> Option<Duration> {
+    let last_modified = parse_http_date(headers.get(LAST_MODIFIED)?.to_str().ok()?).ok()?;
+    let date = headers
+        .get(DATE)
+        .and_then(|d| d.to_str().ok())
+        .and_then(|d| parse_http_date(d).ok())
+        .unwrap_or_else(SystemTime::now);
+    let age = date.duration_since(last_modified).unwrap_or_default();
+    Some((age / 10).min(Duration::from_secs(max_ttl)))
+}
+
+/// freshness lifetime given by the backend service in the response headers.
+/// Cache-Control no-cache makes the response stale at once like max-age=0, it must be revalidated on every use.
+/// Otherwise Cache-Control s-maxage, then max-age, then Expires, then Surrogate-Control max-age.
+fn explicit_freshness(headers: &HeaderMap) -> Option<Duration> {
+    let cache_control = CacheControl::from_headers(headers);
+    if cache_control.no_cache {
+        return Some(Duration::ZERO);
+    }
+    if let Some(max_age) = cache_control.s_maxage.or(cache_control.max_age) {
+        return Some(Duration::from_secs(max_age));
+    }
+    if let Some(expires) = headers.get(EXPIRES) {
+        // an invalid date like 0 means already expired.
+        let Some(expires) = expires.to_str().ok().and_then(|e| parse_http_date(e).ok()) else {
+            return Some(Duration::ZERO);
+        };
+        let date = headers
+            .get(DATE)
+            .and_then(|d| d.to_str().ok())
+            .and_then(|d| parse_http_date(d).ok())
+            .unwrap_or_else(SystemTime::now);
+        return Some(expires.duration_since(date).unwrap_or_default());
+    }
+    CacheControl::from_surrogate_headers(headers)
+        .max_age
+        .map(Duration::from_secs)
+}
 
 impl Cache {
-    pub fn new(config: &Config) -> Cache {
+    /// the tags of the entries removed from the cache are removed from the index, whatever the cause of the removal.
+    pub fn new(config: &Config, index: Arc<IndexCache>) -> Cache {
         Self(
             MokaCache::builder()
-                .name("mnemosyne")
-                .time_to_idle(Duration::from_secs(config.cache.expiration))
-                .weigher(
-                    |_key: &Uuid, (s, h, b): &(StatusCode, HeaderMap, Bytes)| -> u32 {
-                        let s = s.to_string().get_size() as u32;
-                        let h = h.iter().fold(0, |acc, x| {
-                            acc + (x.0.to_string().get_size()
-                                + x.1.to_str().unwrap().to_string().get_size())
-                                as u32
-                        });
-                        let b = b.len() as u32;
-                        // note that the size overhead of the index cache is not taken into account.
-                        // could take about 100B per entry.
-                        s + h + b
-                    },
-                )
+                .name(&config.cache.name)
+                .eviction_listener(move |uuid: Arc<Uuid>, _, cause| {
+                    // a replaced entry keeps its uuid and its tags.
+                    if cause != RemovalCause::Replaced {
+                        index.untag_entry(&uuid);
+                    }
+                })
+                .expire_after(EntryExpiry {
+                    idle: Duration::from_secs(config.cache.expiration),
+                })
+                // note that the size overhead of the index cache is not taken into account.
+                // could take about 100B per entry.
+                .weigher(|_key: &Uuid, entry: &CacheEntry| -> u32 { entry.stored_size() })
                 // This cache will hold up to 32MiB of values.
                 .max_capacity(config.cache.size_limit * 1024 * 1024)
                 .build_with_hasher(ahash::RandomState::new()),
+            config
+                .cache
+                .compress_bodies
+                .then_some(config.cache.compress_min_bytes as usize),
         )
     }
-    pub fn check_etag(&self, headers: &HeaderMap) -> bool {
-        if let Some(etag) = headers.get(ETAG) {
-            if let Ok(str) = etag.to_str() {
-                if let Ok(uuid) = Uuid::from_str(str) {
-                    return self.contains_key(&uuid);
+    /// store an entry, its body is compressed if enabled and large enough.
+    /// Bodies already encoded by the backend service are stored as is.
+    pub async fn insert(&self, uuid: Uuid, mut entry: CacheEntry) {
+        if let Some(min) = self.1 {
+            if !entry.compressed
+                && entry.body.len() > min
+                && !entry.headers.contains_key(CONTENT_ENCODING)
+            {
+                let compressed = compress(&entry.body);
+                // incompressible bodies are kept as is
+                if compressed.len() < entry.body.len() {
+                    entry.body = compressed;
+                    entry.compressed = true;
                 }
             }
         }
-        false
+        self.0.insert(uuid, entry).await;
+    }
+    /// entry for this uuid with its body decompressed.
+    pub async fn get(&self, uuid: &Uuid) -> Option<CacheEntry> {
+        self.0.get(uuid).await?.decompressed()
+    }
+}
+
+fn compress(body: &[u8]) -> Bytes {
+    let mut compressed = vec![];
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+        // writing to a Vec does not fail
+        writer.write_all(body).unwrap();
+    }
+    compressed.into()
+}
+
+fn decompress(body: &[u8]) -> Option<Bytes> {
+    let mut decompressed = vec![];
+    brotli::Decompressor::new(body, 4096)
+        .read_to_end(&mut decompressed)
+        .ok()?;
+    Some(decompressed.into())
+}
+
+/// expiration of the entries in the cache.
+/// An entry that can not be revalidated is useless once stale, it expires at the end of its freshness lifetime.
+/// Others expire after being unused for the idle duration, the configured expiration or the one of their endpoint.
+struct EntryExpiry {
+    idle: Duration,
+}
+
+impl EntryExpiry {
+    fn idle(&self, entry: &CacheEntry) -> Duration {
+        entry.expiration.unwrap_or(self.idle)
+    }
+    fn time_to_live(&self, entry: &CacheEntry) -> Duration {
+        match entry.freshness {
+            // kept during the stale-while-revalidate or stale-if-error window, to be served while a new response is fetched.
+            Some(freshness) if !entry.can_revalidate() => (freshness + entry.stale_window())
+                .saturating_sub(entry.age())
+                .min(self.idle(entry)),
+            _ => self.idle(entry),
+        }
+    }
+}
+
+impl Expiry<Uuid, CacheEntry> for EntryExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &Uuid,
+        entry: &CacheEntry,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(self.time_to_live(entry))
+    }
+    fn expire_after_read(
+        &self,
+        _key: &Uuid,
+        entry: &CacheEntry,
+        _read_at: Instant,
+        duration_until_expiry: Option<Duration>,
+        _last_modified_at: Instant,
+    ) -> Option<Duration> {
+        if entry.freshness.is_some() && !entry.can_revalidate() {
+            // reading does not extend the freshness lifetime
+            return duration_until_expiry;
+        }
+        Some(self.idle(entry))
+    }
+    fn expire_after_update(
+        &self,
+        _key: &Uuid,
+        entry: &CacheEntry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(self.time_to_live(entry))
     }
 }
+
+/// true if the request is conditional on the entry resolved for it, a 304 can then be returned.
+/// If-None-Match is a comma separated list of etags, quoted or not and possibly weak, or `*` for any entry.
+/// The etag is the uuid of the entry, or the etag of the backend service if it gave one, compared with the weak comparison.
+/// An etag of another resource present in the cache does not match.
+/// A bare uuid in an ETag request header is still accepted for older integrations.
+pub fn check_etag(headers: &HeaderMap, uuid: &Uuid, backend_etag: Option<&HeaderValue>) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        let backend_etag = backend_etag.and_then(|etag| etag.to_str().ok());
+        return entity_tags(if_none_match).any(|etag| {
+            etag == "*"
+                || parse_etag(etag).is_some_and(|etag| &etag == uuid)
+                || backend_etag.is_some_and(|backend| weak_eq(etag, backend))
+        });
+    }
+    headers
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .and_then(parse_etag)
+        .is_some_and(|etag| &etag == uuid)
+}
+
+/// true if the entry was not modified since the date of the If-Modified-Since request header, a 304 can then be returned.
+/// If-Modified-Since is ignored when the request has If-None-Match, or when the entry has no valid Last-Modified date.
+pub fn check_modified_since(headers: &HeaderMap, entry: &CacheEntry) -> bool {
+    if headers.contains_key(IF_NONE_MATCH) {
+        return false;
+    }
+    let Some(since) = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|since| parse_http_date(since.to_str().ok()?).ok())
+    else {
+        return false;
+    };
+    entry
+        .last_modified()
+        .and_then(|modified| parse_http_date(modified.to_str().ok()?).ok())
+        .is_some_and(|modified| modified <= since)
+}
+
+/// true if the range of the request can be served from the entry, false if the full entry must be served instead.
+/// The If-Range validator must be the strong ETag given to the client or exactly the Last-Modified date of the entry, the range is always served without If-Range.
+pub fn check_if_range(headers: &HeaderMap, entry: &CacheEntry) -> bool {
+    let Some(if_range) = headers.get(IF_RANGE).and_then(|v| v.to_str().ok()) else {
+        return true;
+    };
+    let if_range = if_range.trim();
+    if let Ok(date) = parse_http_date(if_range) {
+        return entry
+            .last_modified()
+            .and_then(|modified| parse_http_date(modified.to_str().ok()?).ok())
+            .is_some_and(|modified| modified == date);
+    }
+    // strong comparison, quotes are optional since the ETag added by Mnemosyne is a bare uuid.
+    let etag = entry.headers.get(ETAG).and_then(|etag| etag.to_str().ok());
+    !if_range.starts_with("W/")
+        && etag.is_some_and(|etag| !etag.trim().starts_with("W/") && weak_eq(etag, if_range))
+}
+
+/// entity tags of a comma separated list, a comma inside the quotes of a tag does not separate it.
+fn entity_tags(list: &str) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    list.split(move |c| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        c == ',' && !quoted
+    })
+    .map(str::trim)
+    .filter(|etag| !etag.is_empty())
+}
+
+/// opaque part of an entity tag, without the weak indicator and the quotes which are optional.
+fn opaque_tag(etag: &str) -> &str {
+    let etag = etag.trim();
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    etag.strip_prefix('"')
+        .and_then(|e| e.strip_suffix('"'))
+        .unwrap_or(etag)
+}
+
+/// weak comparison of two entity tags, the weak indicator is ignored.
+fn weak_eq(a: &str, b: &str) -> bool {
+    opaque_tag(a) == opaque_tag(b)
+}
+
+/// uuid of an entity tag, the weak indicator and quotes are optional.
+fn parse_etag(etag: &str) -> Option<Uuid> {
+    Uuid::from_str(opaque_tag(etag)).ok()
+}
@@ -0,0 +1,69 @@
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CACHE_CONTROL, PRAGMA};
+
+/// Surrogate-Control header, Cache-Control directives aimed at proxies.
+pub const SURROGATE_CONTROL: HeaderName = HeaderName::from_static("surrogate-control");
+
+/// directives of the Cache-Control headers that Mnemosyne takes into account.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct CacheControl {
+    /// max-age directive, in seconds.
+    pub max_age: Option<u64>,
+    /// s-maxage directive, in seconds. Takes precedence over max-age for shared caches like Mnemosyne.
+    pub s_maxage: Option<u64>,
+    /// must-revalidate or proxy-revalidate directive, a stale entry must never be served without a successful revalidation.
+    pub must_revalidate: bool,
+    /// no-cache directive, in a request the client asks for a response validated by the backend service.
+    pub no_cache: bool,
+    /// no-store directive, the response must not be stored.
+    pub no_store: bool,
+    /// private directive, the response is for a single user and must not be stored by a shared cache.
+    pub private: bool,
+    /// stale-while-revalidate directive, in seconds after the freshness lifetime during which the stale response can be served while it is revalidated.
+    pub stale_while_revalidate: Option<u64>,
+}
+
+impl CacheControl {
+    /// parse every Cache-Control header of a request or response.
+    /// unknown or malformed directives are ignored.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Self::parse(headers.get_all(CACHE_CONTROL).iter())
+    }
+    /// true if the request asks for a response validated by the backend service, with Cache-Control or Pragma no-cache.
+    pub fn request_no_cache(headers: &HeaderMap) -> bool {
+        Self::from_headers(headers).no_cache
+            || headers
+                .get_all(PRAGMA)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .any(|value| value.trim().eq_ignore_ascii_case("no-cache"))
+    }
+    /// parse every Surrogate-Control header of a response.
+    pub fn from_surrogate_headers(headers: &HeaderMap) -> Self {
+        Self::parse(headers.get_all(SURROGATE_CONTROL).iter())
+    }
+    fn parse<'a>(values: impl Iterator<Item = &'a HeaderValue>) -> Self {
+        let mut cache_control = CacheControl::default();
+        values
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .for_each(|directive| {
+                let (name, value) = match directive.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (directive.trim(), None),
+                };
+                match name.to_ascii_lowercase().as_str() {
+                    "max-age" => cache_control.max_age = value.and_then(|v| v.parse().ok()),
+                    "s-maxage" => cache_control.s_maxage = value.and_then(|v| v.parse().ok()),
+                    "must-revalidate" | "proxy-revalidate" => cache_control.must_revalidate = true,
+                    "no-cache" => cache_control.no_cache = true,
+                    "no-store" => cache_control.no_store = true,
+                    "private" => cache_control.private = true,
+                    "stale-while-revalidate" => {
+                        cache_control.stale_while_revalidate = value.and_then(|v| v.parse().ok())
+                    }
+                    _ => {}
+                }
+            });
+        cache_control
+    }
+}
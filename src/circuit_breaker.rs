@@ -0,0 +1,66 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ahash::HashMap;
+use url::Url;
+
+/// state of the connections to each backend service, keyed by host and port.
+/// After too many consecutive failures, the breaker of a backend service opens and its requests fail at once.
+/// Once the cooldown elapsed, one request is let through to probe it: the breaker closes if it succeeds, or opens for another cooldown.
+#[derive(Clone, Default)]
+pub struct CircuitBreakers(Arc<Mutex<HashMap<String, Breaker>>>);
+
+#[derive(Default)]
+struct Breaker {
+    /// consecutive failed requests.
+    failures: u32,
+    /// when the breaker opened or the last probe was let through, none if closed.
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreakers {
+    /// true if a request can be sent to the backend service.
+    /// The first request after the cooldown is the probe, the next ones are refused until it completes or another cooldown elapsed.
+    pub fn allow(&self, backend: &str, cooldown: Duration) -> bool {
+        let mut breakers = self.0.lock().unwrap();
+        let Some(opened_at) = breakers.get_mut(backend).and_then(|b| b.opened_at.as_mut()) else {
+            return true;
+        };
+        if opened_at.elapsed() < cooldown {
+            return false;
+        }
+        *opened_at = Instant::now();
+        true
+    }
+    /// true if the breaker of the backend service is open and its cooldown not elapsed, without letting a probe through.
+    pub fn is_open(&self, backend: &str, cooldown: Duration) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(backend)
+            .and_then(|b| b.opened_at)
+            .is_some_and(|opened_at| opened_at.elapsed() < cooldown)
+    }
+    /// the backend service answered, its breaker closes.
+    pub fn success(&self, backend: &str) {
+        self.0.lock().unwrap().remove(backend);
+    }
+    /// the request to the backend service failed, the breaker opens once threshold failures follow each other.
+    pub fn failure(&self, backend: &str, threshold: u32) {
+        let mut breakers = self.0.lock().unwrap();
+        let breaker = breakers.entry(backend.to_string()).or_default();
+        breaker.failures += 1;
+        if breaker.failures >= threshold {
+            breaker.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+/// key of the breaker of a backend service, its host and port whatever the path.
+pub fn breaker_key(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(format!(
+        "{host}:{}",
+        url.port_or_known_default().unwrap_or_default()
+    ))
+}
@@ -1,72 +1,1279 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use axum::http::{uri::PathAndQuery, HeaderValue};
+use anyhow::{bail, Context};
+
+use axum::body::Bytes;
+use axum::http::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::{uri::PathAndQuery, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use rand::Rng;
+use regex::Regex;
 use reqwest::Url;
-use serde::{Deserialize, Serialize};
-use tracing::debug;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::{debug, warn};
+use url::Host;
+/// file the configuration is loaded from, unless another one is given.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/mnemosyne/config.toml";
+/// environment variable giving the path of the configuration file.
+pub const CONFIG_PATH_ENV: &str = "MNEMOSYNE_CONFIG";
+
+/// path of the configuration file from the command line arguments, without the program name, and the environment variable.
+/// `--config <path>` or `--config=<path>` takes precedence over MNEMOSYNE_CONFIG, then the default path is used.
+pub fn config_path(
+    args: impl IntoIterator<Item = String>,
+    env: Option<String>,
+) -> anyhow::Result<PathBuf> {
+    let mut args = args.into_iter();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            let Some(value) = args.next() else {
+                bail!("{arg} requires a path");
+            };
+            path = Some(value);
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            path = Some(value.to_string());
+        } else {
+            bail!("unknown argument {arg}, usage: mnemosyne [--config <path>]");
+        }
+    }
+    Ok(PathBuf::from(
+        path.or(env.filter(|e| !e.is_empty()))
+            .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string()),
+    ))
+}
+
 /// configuration struct.
 /// Example:
 /// listen_port: 9834,
-/// endpoints: [("/api1", "127.0.0.1:3998")]
-/// request /api1/abc
+/// endpoints: [{ host = "example.com", prefix = "/api1", backends = [{ url = "http://127.0.0.1:3998" }] }]
+/// request example.com/api1/abc
 /// will do 127.0.0.1:3998/abc
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct Config {
     /// address and port to which Mnemosyne will listen for incoming requests.
     pub listen_address: SocketAddr,
-    /// String is the HOST mnemosyne will accept request and redirect them to Url
-    pub endpoints: Vec<(String, Url)>,
+    /// additional addresses Mnemosyne will listen on, each with its own fallback policy.
+    pub listeners: Vec<Listener>,
+    /// path of a Unix domain socket Mnemosyne will also listen on, for a reverse proxy on the same machine.
+    /// A file left at this path by a previous run is removed. The socket serves plain HTTP with the fallback allowed.
+    pub listen_uds: Option<PathBuf>,
+    /// HOST mnemosyne will accept request and redirect them to the backend services of the endpoint.
+    /// the format ["HOST", "Url"] of previous versions is still accepted.
+    #[serde(deserialize_with = "deserialize_endpoints")]
+    pub endpoints: Vec<Endpoint>,
     /// if none of the request contained recognized uri or if you want to redirect every request to one backend.
     pub fall_back_endpoint: Url,
     /// cache backend configuration
     pub cache: CacheConfig,
+    /// read-only mode, only responses present in cache are served.
+    /// The backend services are never requested, a cache miss returns 503.
+    pub maintenance: bool,
+    /// if false, every request is proxied to the backend services as is, the cache is neither read nor filled and no ETag is added.
+    /// Useful to find out if an issue comes from the cache or from the backend services.
+    pub caching_enabled: bool,
+    /// in seconds, value of the Retry-After header of 503 responses. 0 to not send the header.
+    pub retry_after: u64,
+    /// in milliseconds, identical purges of the admin API within this window are done only once. 0 to disable.
+    pub purge_dedup_window: u64,
+    /// maximum number of purges of the admin API scanning the index at the same time, others are refused with 503. 0 for no limit.
+    pub max_concurrent_purges: usize,
+    /// in milliseconds, maximum wait for the next chunk of a body from a backend service before answering 504. 0 to wait indefinitely.
+    pub backend_idle_timeout_ms: u64,
+    /// attempts to connect to a backend service for idempotent requests without body, 1 to never retry.
+    /// Only failures to connect are retried, never a response received from the backend service.
+    pub backend_max_attempts: u32,
+    /// in milliseconds, wait before the first retry, doubled for every next one.
+    pub backend_retry_delay_ms: u64,
+    /// consecutive failed connections to a backend service before its requests fail at once with 503, or are served from a stale entry.
+    /// 0 to disable the circuit breaker, the endpoints with several backend services then do not skip the ones that are down.
+    pub breaker_threshold: u32,
+    /// in milliseconds, time a backend service is not requested once its circuit breaker opened, before probing it with one request.
+    pub breaker_cooldown_ms: u64,
+    /// invalidate the cached entries of a HOST when its endpoint is modified or deleted with the admin API.
+    /// The entries of every HOST matching the endpoint are invalidated, ignoring case or with its pattern.
+    pub invalidate_on_config_change: bool,
+    /// requests with a HOST absent from "endpoints" are refused instead of being sent to the fallback endpoint.
+    pub strict_routing: bool,
+    /// status of the response to requests refused by strict routing.
+    pub unknown_host_status: UnknownHostStatus,
+    /// format of the access log, one line per request, disabled by default.
+    pub access_log: AccessLogFormat,
+    /// HOST given to requests without one, like those of HTTP/1.0 clients, so they are routed and cached normally.
+    pub default_host: Option<String>,
+    /// refuse requests without HOST with 400 if there is no default_host, instead of sending them to the fallback without caching.
+    pub reject_missing_host: bool,
+    /// certificate and private key to serve HTTPS on listen_address, plain HTTP if none.
+    /// The additional listeners always serve plain HTTP.
+    pub tls: Option<TlsConfig>,
+    /// token required in the header `Authorization: Bearer <token>` of the requests to the API under /api/1.
+    /// The API is open to anyone reaching Mnemosyne if none.
+    pub admin_token: Option<String>,
+    /// sort the parameters of the query by name then value in the cache key, so their order does not create separate entries.
+    /// The backend services still receive the query as sent by the client.
+    pub normalize_query: bool,
+}
+
+/// paths of PEM files for the TLS listener.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    /// certificate chain, the certificate of Mnemosyne first.
+    pub cert_path: PathBuf,
+    /// private key of the certificate.
+    pub key_path: PathBuf,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             listen_address: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 9830)),
+            listeners: Vec::new(),
+            listen_uds: None,
             endpoints: Default::default(),
             cache: Default::default(),
             fall_back_endpoint: Url::parse("http://127.0.0.1:1000").unwrap(),
+            maintenance: false,
+            caching_enabled: true,
+            retry_after: 60,
+            purge_dedup_window: 0,
+            max_concurrent_purges: 4,
+            backend_idle_timeout_ms: 30000,
+            backend_max_attempts: 1,
+            backend_retry_delay_ms: 100,
+            breaker_threshold: 0,
+            breaker_cooldown_ms: 30000,
+            invalidate_on_config_change: false,
+            strict_routing: false,
+            unknown_host_status: UnknownHostStatus::default(),
+            access_log: AccessLogFormat::default(),
+            default_host: None,
+            reject_missing_host: false,
+            tls: None,
+            admin_token: None,
+            normalize_query: false,
         }
     }
 }
 
 impl Config {
-    pub fn to_backend_uri(&self, uri_req: &PathAndQuery, host: &Option<HeaderValue>) -> Url {
-        //todo use regex to get the start of the line
-        if let Some(host) = host {
-            if let Ok(host) = host.to_str() {
-                if let Some((endpoint, url)) = self.endpoints.iter().find(|b| host == b.0) {
-                    debug!("endpoint detected: {endpoint}");
-                    debug!("url: {url}");
-                    return Url::parse(&format!("{}{}", url, uri_req).replace("//", "/"))
-                        .expect("could not parse to Url");
+    /// create a [`ConfigBuilder`] starting from the default configuration.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+    /// check the configuration before using it, so a mistake is reported at once instead of on the first request.
+    /// Backend urls must be http or https urls with a host, endpoints must have a backend to send requests to, and no url may point to Mnemosyne itself.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        check_backend_url(&self.fall_back_endpoint)
+            .with_context(|| format!("invalid fallback endpoint {}", self.fall_back_endpoint))?;
+        for endpoint in &self.endpoints {
+            let host = &endpoint.host;
+            if host.is_empty() {
+                bail!("endpoint without HOST");
+            }
+            endpoint
+                .clone()
+                .compile()
+                .with_context(|| format!("invalid pattern for the endpoint {host}"))?;
+            if endpoint.backends.iter().all(|b| b.weight == 0) {
+                bail!("endpoint {host} has no backend with a weight above 0");
+            }
+            for backend in &endpoint.backends {
+                check_backend_url(&backend.url).with_context(|| {
+                    format!(
+                        "invalid backend url {} for the endpoint {host}",
+                        backend.url
+                    )
+                })?;
+            }
+            if let Some(prefix) = endpoint.prefix.as_ref().filter(|p| !p.starts_with('/')) {
+                bail!("prefix {prefix} of the endpoint {host} does not start with /");
+            }
+            for (name, value) in [
+                ("host_header", &endpoint.host_header),
+                ("client_cache_control", &endpoint.client_cache_control),
+            ] {
+                if let Some(value) = value.as_ref().filter(|v| HeaderValue::from_str(v).is_err()) {
+                    bail!("{name} {value:?} of the endpoint {host} is not a valid header value");
                 }
             }
         }
+        let listeners = self.all_listeners();
+        if let Some((i, listener)) = listeners
+            .iter()
+            .enumerate()
+            .find(|(i, l)| listeners[..*i].iter().any(|o| o.address == l.address))
+        {
+            bail!(
+                "address {} is listened on twice (listener {i})",
+                listener.address
+            );
+        }
+        if let Some(host) = self
+            .default_host
+            .as_ref()
+            .filter(|h| HeaderValue::from_str(h).is_err())
+        {
+            bail!("default_host {host:?} is not a valid header value");
+        }
+        if self
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            bail!("admin_token is empty, remove it to leave the API open");
+        }
+        self.check_loop()
+    }
+    /// refuse backend urls pointing to the address Mnemosyne listens on, the requests would loop infinitely.
+    pub fn check_loop(&self) -> anyhow::Result<()> {
+        if self.is_self_referential(&self.fall_back_endpoint) {
+            bail!(
+                "fallback endpoint {} points to Mnemosyne itself listening on {}, requests would loop",
+                self.fall_back_endpoint,
+                self.listen_address
+            );
+        }
+        for endpoint in &self.endpoints {
+            if let Some(backend) = endpoint
+                .backends
+                .iter()
+                .find(|b| self.is_self_referential(&b.url))
+            {
+                bail!(
+                    "endpoint {} with url {} points to Mnemosyne itself listening on {}, requests would loop",
+                    endpoint.host,
+                    backend.url,
+                    self.listen_address
+                );
+            }
+        }
+        Ok(())
+    }
+    /// maximum wait for the next chunk of a body from a backend service, none if disabled.
+    pub fn backend_idle_timeout(&self) -> Option<Duration> {
+        (self.backend_idle_timeout_ms > 0)
+            .then(|| Duration::from_millis(self.backend_idle_timeout_ms))
+    }
+    /// retries of the requests failing to connect to a backend service.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.backend_max_attempts.max(1),
+            base_delay: Duration::from_millis(self.backend_retry_delay_ms),
+        }
+    }
+    /// failures opening the circuit breaker of a backend service and its cooldown, none if disabled.
+    pub fn circuit_breaker(&self) -> Option<(u32, Duration)> {
+        (self.breaker_threshold > 0).then(|| {
+            (
+                self.breaker_threshold,
+                Duration::from_millis(self.breaker_cooldown_ms),
+            )
+        })
+    }
+    /// every address Mnemosyne listens on, the listen_address first with the fallback allowed.
+    pub fn all_listeners(&self) -> Vec<Listener> {
+        let mut listeners = vec![Listener {
+            address: self.listen_address,
+            fallback: true,
+        }];
+        listeners.extend(self.listeners.iter().cloned());
+        listeners
+    }
+    /// true if the url targets an address Mnemosyne listens on.
+    /// loopback and unspecified addresses are considered the same local host.
+    pub fn is_self_referential(&self, url: &Url) -> bool {
+        self.all_listeners()
+            .iter()
+            .any(|l| targets_address(url, l.address))
+    }
+    /// url of the backend service for a request, the prefix of its endpoint is removed from the path.
+    pub fn to_backend_uri(&self, uri_req: &PathAndQuery, host: &Option<HeaderValue>) -> Url {
+        self.to_healthy_backend_uri(uri_req, host, |_| true)
+    }
+    /// url of the backend service for a request, picked among the healthy backends of its endpoint.
+    pub fn to_healthy_backend_uri(
+        &self,
+        uri_req: &PathAndQuery,
+        host: &Option<HeaderValue>,
+        healthy: impl Fn(&Url) -> bool,
+    ) -> Url {
+        if let Some(endpoint) = self.find_endpoint(host, uri_req) {
+            debug!("endpoint detected: {}", endpoint.host);
+            if let Some(url) = endpoint.pick_backend(healthy) {
+                debug!("url: {url}");
+                return join_backend(url, &endpoint.strip_prefix(uri_req));
+            }
+        }
         // no uri recognized, using fallback backend
-        Url::parse(&format!("{}{}", self.fall_back_endpoint, uri_req).replace("//", "/"))
-            .expect("could not parse to Url")
+        join_backend(&self.fall_back_endpoint, uri_req)
+    }
+    /// HOST to send to the backend service instead of the one of the request, if its endpoint does not preserve it.
+    pub fn backend_host(
+        &self,
+        host: &Option<HeaderValue>,
+        path: &PathAndQuery,
+        url: &Url,
+    ) -> Option<HeaderValue> {
+        let endpoint = self
+            .find_endpoint(host, path)
+            .filter(|e| !e.preserve_host)?;
+        let value = match (&endpoint.host_header, url.host_str(), url.port()) {
+            (Some(host), _, _) => host.clone(),
+            (None, Some(host), Some(port)) => format!("{host}:{port}"),
+            (None, host, None) => host?.to_string(),
+            (None, None, Some(_)) => return None,
+        };
+        HeaderValue::from_str(&value).ok()
+    }
+    /// Cache-Control to send to clients for the HOST of a request, if its endpoint overrides it.
+    pub fn client_cache_control(
+        &self,
+        host: &Option<HeaderValue>,
+        path: &PathAndQuery,
+    ) -> Option<HeaderValue> {
+        let value = self
+            .find_endpoint(host, path)?
+            .client_cache_control
+            .as_deref()?;
+        HeaderValue::from_str(value).ok()
+    }
+    /// cache settings for the HOST of a request, the global ones with the overrides of its endpoint.
+    pub fn cache_config(&self, host: &Option<HeaderValue>, path: &PathAndQuery) -> CacheConfig {
+        let mut cache = self.cache.clone();
+        if let Some(endpoint) = self.find_endpoint(host, path) {
+            let o = &endpoint.cache;
+            cache.expiration = o.expiration.unwrap_or(cache.expiration);
+            cache.default_ttl_secs = o.default_ttl_secs.unwrap_or(cache.default_ttl_secs);
+            cache.max_cacheable_body_bytes = o
+                .max_cacheable_body_bytes
+                .unwrap_or(cache.max_cacheable_body_bytes);
+            if let Some(statuses) = &o.cacheable_statuses {
+                cache.cacheable_statuses = statuses.clone();
+            }
+        }
+        cache
+    }
+    /// endpoint configured for the HOST and the path of a request.
+    /// Endpoints with exactly this HOST, ignoring case, are preferred, then those with a pattern matching it.
+    /// Among them, the one with the longest prefix matching the path is chosen, the first one listed if several have the same.
+    pub fn find_endpoint(
+        &self,
+        host: &Option<HeaderValue>,
+        path: &PathAndQuery,
+    ) -> Option<&Endpoint> {
+        let host = host.as_ref()?.to_str().ok()?;
+        let path = path.path();
+        longest_prefix(
+            self.endpoints
+                .iter()
+                .filter(|e| e.host.eq_ignore_ascii_case(host)),
+            path,
+        )
+        .or_else(|| longest_prefix(self.endpoints.iter().filter(|e| e.matches(host)), path))
     }
 }
 
+/// endpoint with the longest prefix matching the path, the first one if several have the same.
+fn longest_prefix<'a>(
+    endpoints: impl Iterator<Item = &'a Endpoint>,
+    path: &str,
+) -> Option<&'a Endpoint> {
+    let mut best: Option<(usize, &Endpoint)> = None;
+    for endpoint in endpoints {
+        let Some(len) = endpoint.prefix_len(path) else {
+            continue;
+        };
+        if best.is_none_or(|(best_len, _)| len > best_len) {
+            best = Some((len, endpoint));
+        }
+    }
+    best.map(|(_, endpoint)| endpoint)
+}
+
+/// true if the url targets the listen address.
+fn targets_address(url: &Url, listen_address: SocketAddr) -> bool {
+    if url.port_or_known_default() != Some(listen_address.port()) {
+        return false;
+    }
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        Some(Host::Domain(domain)) if domain.eq_ignore_ascii_case("localhost") => {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        }
+        _ => return false,
+    };
+    let listen = listen_address.ip();
+    let local = |ip: IpAddr| ip.is_loopback() || ip.is_unspecified();
+    ip == listen || (local(ip) && local(listen))
+}
+
+/// retries of the connections to the backend services, with an exponential backoff.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// attempts in total, at least 1.
+    pub max_attempts: u32,
+    /// wait before the first retry, doubled for every next one.
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// wait before the retry following a failed attempt, the first attempt being 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+/// address Mnemosyne listens on.
+/// Can be given as a plain address, the fallback is then allowed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "ListenerFormat")]
+pub struct Listener {
+    pub address: SocketAddr,
+    /// requests with a HOST absent from "endpoints" are sent to the fallback endpoint.
+    /// If false, they are refused with the unknown_host_status.
+    pub fallback: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListenerFormat {
+    Address(SocketAddr),
+    Table {
+        address: SocketAddr,
+        #[serde(default = "default_fallback")]
+        fallback: bool,
+    },
+}
+
+impl From<ListenerFormat> for Listener {
+    fn from(format: ListenerFormat) -> Self {
+        match format {
+            ListenerFormat::Address(address) => Listener {
+                address,
+                fallback: true,
+            },
+            ListenerFormat::Table { address, fallback } => Listener { address, fallback },
+        }
+    }
+}
+
+fn default_fallback() -> bool {
+    true
+}
+
+fn default_preserve_host() -> bool {
+    true
+}
+
+/// status returned for a HOST unknown in strict routing mode.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownHostStatus {
+    /// 404 Not Found
+    NotFound,
+    /// 421 Misdirected Request, tells HTTP/2 clients coalescing connections to retry on a new one.
+    #[default]
+    MisdirectedRequest,
+}
+
+impl UnknownHostStatus {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UnknownHostStatus::NotFound => StatusCode::NOT_FOUND,
+            UnknownHostStatus::MisdirectedRequest => StatusCode::MISDIRECTED_REQUEST,
+        }
+    }
+}
+
+/// format of the lines of the access log.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// no access log.
+    #[default]
+    Off,
+    /// fields separated by spaces.
+    Plain,
+    /// one JSON object per line.
+    Json,
+}
+
+/// backend services for a HOST.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Endpoint {
+    /// HOST header of the requests redirected to the backend services.
+    /// Can be a pattern: `*` matches any characters, like `*.example.com`, and a HOST starting with `~` is a regular expression.
+    /// HOSTs and `*` patterns ignore case. A regular expression is used as is: it is case sensitive and matches anywhere in the HOST unless anchored with `^` and `$`.
+    pub host: String,
+    /// one backend service is picked for each request, according to its weight.
+    /// Cache entries are keyed by request, not by backend: responses from a canary backend can be served from cache to every client.
+    /// To keep them apart, give the canary its own HOST in a distinct endpoint, or disable caching for this endpoint with an empty `cache.cacheable_statuses`.
+    pub backends: Vec<Backend>,
+    /// how the backend service of a request is picked, at random by default.
+    /// A backend service that is down is only skipped when breaker_threshold is set, otherwise it keeps receiving its share of the requests.
+    #[serde(default)]
+    pub balance: Balance,
+    /// find/replace rules applied to the text bodies of the responses, none by default.
+    #[serde(default)]
+    pub rewrite: BodyRewrite,
+    /// Cache-Control sent to the clients instead of the one of the backend service, on responses from the cache or not.
+    /// The freshness of the cache entries still follows the backend service.
+    #[serde(default)]
+    pub client_cache_control: Option<String>,
+    /// cache settings replacing the global ones for the responses of this endpoint.
+    #[serde(default)]
+    pub cache: CacheOverride,
+    /// only the requests with a path starting with this prefix are routed to this endpoint, the prefix is removed from the path sent to the backend service.
+    /// A prefix matches whole segments: "/api" matches "/api" and "/api/abc" but not "/apis".
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// send the HOST of the request to the backend service.
+    /// If false, the HOST is host_header, or the host and port of the backend url.
+    #[serde(default = "default_preserve_host")]
+    pub preserve_host: bool,
+    /// HOST sent to the backend service when preserve_host is false.
+    #[serde(default)]
+    pub host_header: Option<String>,
+    /// pattern of the HOST compiled once, none if the HOST is not a pattern.
+    #[serde(skip)]
+    matcher: Option<Regex>,
+    /// turn of the next backend service in round robin.
+    #[serde(skip)]
+    next: Arc<AtomicUsize>,
+}
+
+/// balancing of the requests between the backend services of an endpoint.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// each backend service is picked at random.
+    #[default]
+    Random,
+    /// the backend services are picked in turn.
+    RoundRobin,
+}
+
+/// cache settings of an endpoint, each one not given is taken from the global [`CacheConfig`].
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct CacheOverride {
+    /// in seconds, expiration of the entries after their last request.
+    pub expiration: Option<u64>,
+    /// in seconds, freshness lifetime of responses without freshness information from the backend service.
+    pub default_ttl_secs: Option<u64>,
+    /// in bytes, the maximum size of a response body that will be cached.
+    #[serde(alias = "max_entry_size")]
+    pub max_cacheable_body_bytes: Option<u64>,
+    /// status codes of the responses that can be cached.
+    pub cacheable_statuses: Option<Vec<u16>>,
+}
+
+impl Endpoint {
+    /// endpoint redirecting requests to a single backend service.
+    /// An invalid pattern for the HOST never matches, use [`Endpoint::compile`] to check it.
+    pub fn new(host: impl Into<String>, url: Url) -> Self {
+        let mut endpoint = Self {
+            host: host.into(),
+            backends: vec![Backend { url, weight: 1 }],
+            balance: Balance::default(),
+            rewrite: BodyRewrite::default(),
+            client_cache_control: None,
+            cache: CacheOverride::default(),
+            prefix: None,
+            preserve_host: true,
+            host_header: None,
+            matcher: None,
+            next: Arc::default(),
+        };
+        if let Err(err) = endpoint.compile() {
+            warn!("invalid pattern for the endpoint {}: {err}", endpoint.host);
+        }
+        endpoint
+    }
+    /// compile the pattern of the HOST, to call after the HOST is modified.
+    pub fn compile(&mut self) -> Result<(), regex::Error> {
+        self.matcher = if let Some(regex) = self.host.strip_prefix('~') {
+            Some(Regex::new(regex)?)
+        } else if self.host.contains('*') {
+            let glob = self
+                .host
+                .split('*')
+                .map(regex::escape)
+                .collect::<Vec<_>>()
+                .join(".*");
+            Some(Regex::new(&format!("(?i)^{glob}$"))?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+    /// length of the prefix if it matches the path, 0 without prefix.
+    /// None if the path does not start with the prefix.
+    fn prefix_len(&self, path: &str) -> Option<usize> {
+        let prefix = self
+            .prefix
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches('/');
+        let rest = path.strip_prefix(prefix)?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(prefix.len())
+    }
+    /// path of the request without the prefix of the endpoint.
+    pub fn strip_prefix(&self, uri: &PathAndQuery) -> PathAndQuery {
+        let Some(len) = self.prefix_len(uri.path()).filter(|len| *len > 0) else {
+            return uri.clone();
+        };
+        let path = &uri.path()[len..];
+        let path = if path.is_empty() { "/" } else { path };
+        let stripped = match uri.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path.to_string(),
+        };
+        PathAndQuery::from_str(&stripped).unwrap_or_else(|_| uri.clone())
+    }
+    /// true if this HOST is the one of the endpoint ignoring case, or if the HOST of the endpoint is a pattern matching it.
+    pub fn matches(&self, host: &str) -> bool {
+        self.host.eq_ignore_ascii_case(host)
+            || self.matcher.as_ref().is_some_and(|m| m.is_match(host))
+    }
+    /// pick a backend service, weighted by the weight of each backend, at random or in turn according to the balance of the endpoint.
+    /// Backends not healthy are skipped, unless none is.
+    /// None if the endpoint has no backend with a weight above 0.
+    pub fn pick_backend(&self, healthy: impl Fn(&Url) -> bool) -> Option<&Url> {
+        if let [backend] = self.backends.as_slice() {
+            return Some(&backend.url);
+        }
+        let mut backends = self
+            .backends
+            .iter()
+            .filter(|b| b.weight > 0 && healthy(&b.url))
+            .collect::<Vec<_>>();
+        if backends.is_empty() {
+            backends = self.backends.iter().collect();
+        }
+        let total: u64 = backends.iter().map(|b| b.weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = match self.balance {
+            Balance::Random => rand::thread_rng().gen_range(0..total),
+            Balance::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) as u64 % total,
+        };
+        backends.into_iter().find_map(|b| {
+            if pick < b.weight as u64 {
+                Some(&b.url)
+            } else {
+                pick -= b.weight as u64;
+                None
+            }
+        })
+    }
+}
+
+/// rewriting of the bodies of the responses of an endpoint, before they are cached and served.
+/// Useful to replace the internal url of a backend service embedded in HTML or JSON.
+/// Only the bodies buffered to be cached are rewritten: responses passed through the cache,
+/// like the ones of methods other than GET and HEAD, and bodies too large to be cached are streamed untouched.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BodyRewrite {
+    pub rules: Vec<RewriteRule>,
+    /// only bodies with these content types are rewritten, so binary bodies are not touched.
+    pub content_types: Vec<String>,
+}
+
+impl Default for BodyRewrite {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            content_types: vec![
+                "text/html".to_string(),
+                "text/plain".to_string(),
+                "application/json".to_string(),
+            ],
+        }
+    }
+}
+
+/// every occurrence of find is replaced.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RewriteRule {
+    pub find: String,
+    pub replace: String,
+}
+
+impl BodyRewrite {
+    /// apply the rules to a body if its content type is allowed.
+    /// Compressed or non utf-8 bodies are left untouched.
+    /// The Content-Length header is removed if the body is modified, it is computed again when serving.
+    pub fn apply(&self, headers: &mut HeaderMap, body: Bytes) -> Bytes {
+        if self.rules.is_empty() || headers.contains_key(CONTENT_ENCODING) {
+            return body;
+        }
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|c| c.to_str().ok())
+            .and_then(|c| c.split(';').next())
+            .map(|c| c.trim().to_ascii_lowercase());
+        if !content_type.is_some_and(|c| {
+            self.content_types
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(&c))
+        }) {
+            return body;
+        }
+        let Ok(text) = std::str::from_utf8(&body) else {
+            return body;
+        };
+        let rewritten = self.rules.iter().fold(text.to_string(), |text, rule| {
+            text.replace(&rule.find, &rule.replace)
+        });
+        if rewritten == text {
+            return body;
+        }
+        headers.remove(CONTENT_LENGTH);
+        Bytes::from(rewritten)
+    }
+}
+
+/// backend service of an endpoint.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Backend {
+    pub url: Url,
+    /// relative share of the requests of the endpoint sent to this backend.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// accept endpoints as tables or in the previous format ["HOST", "Url"].
+/// The patterns of the HOSTs are compiled once here.
+fn deserialize_endpoints<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Endpoint>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum EndpointFormat {
+        Tuple(String, Url),
+        Table(Endpoint),
+    }
+    Vec::<EndpointFormat>::deserialize(deserializer)?
+        .into_iter()
+        .map(|e| {
+            let mut endpoint = match e {
+                EndpointFormat::Tuple(host, url) => Endpoint::new(host, url),
+                EndpointFormat::Table(endpoint) => endpoint,
+            };
+            // an invalid pattern is refused when loading the configuration.
+            endpoint.compile().map_err(|err| {
+                D::Error::custom(format!("invalid pattern for {}: {err}", endpoint.host))
+            })?;
+            Ok(endpoint)
+        })
+        .collect()
+}
+
+/// a backend service is reached with http or https on a host.
+fn check_backend_url(url: &Url) -> anyhow::Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("scheme {} is not http or https", url.scheme());
+    }
+    if url.host().is_none() {
+        bail!("no host");
+    }
+    Ok(())
+}
+
+/// append the path and query of the request to the url of the backend service.
+/// the path of the backend url is kept as a prefix, so http://backend/v1/ with /abc gives http://backend/v1/abc
+fn join_backend(backend: &Url, uri_req: &PathAndQuery) -> Url {
+    let mut url = backend.clone();
+    let path = format!(
+        "{}/{}",
+        backend.path().trim_end_matches('/'),
+        uri_req.path().trim_start_matches('/')
+    );
+    url.set_path(&path);
+    url.set_query(uri_req.query());
+    url
+}
+
+/// builder to construct a [`Config`] from code instead of a configuration file.
+/// Example:
+/// Config::builder()
+///     .listen_address("127.0.0.1:9830".parse()?)
+///     .endpoint("example.com", Url::parse("http://127.0.0.1:9934")?)
+///     .build()
+#[derive(Default, Debug)]
+pub struct ConfigBuilder(Config);
+
+impl ConfigBuilder {
+    /// address and port to which Mnemosyne will listen for incoming requests.
+    pub fn listen_address(mut self, listen_address: SocketAddr) -> Self {
+        self.0.listen_address = listen_address;
+        self
+    }
+    /// redirect requests with this HOST to the url.
+    pub fn endpoint(mut self, host: impl Into<String>, url: Url) -> Self {
+        self.0.endpoints.push(Endpoint::new(host, url));
+        self
+    }
+    /// add an endpoint, to use options like multiple weighted backends.
+    pub fn with_endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.0.endpoints.push(endpoint);
+        self
+    }
+    /// backend used when no endpoint matches the HOST of the request.
+    pub fn fallback(mut self, url: Url) -> Self {
+        self.0.fall_back_endpoint = url;
+        self
+    }
+    /// cache backend configuration
+    pub fn cache(mut self, cache: CacheConfig) -> Self {
+        self.0.cache = cache;
+        self
+    }
+    pub fn build(self) -> Config {
+        self.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct CacheConfig {
-    /// cache expiration after last request
+    /// name of the cache, given by the cache statistics to tell instances apart.
+    pub name: String,
+    /// in seconds, cache expiration after last request.
+    /// Entries with a freshness lifetime and no validator to revalidate them expire at the end of their freshness instead.
     pub expiration: u64,
     /// in megabytes, the maximum size of memory the cache can take.
     pub size_limit: u64,
+    /// in bytes, the maximum size of a response body that will be buffered to be cached.
+    /// Larger bodies, even without a Content-Length header, are streamed to the client and not cached.
+    #[serde(alias = "max_entry_size")]
+    pub max_cacheable_body_bytes: u64,
+    /// methods of requests that will never be cached, the requests are passed through to the backend service.
+    pub non_cacheable_methods: Vec<String>,
+    /// in seconds, freshness lifetime of responses without Cache-Control max-age, Expires or Surrogate-Control from the backend service.
+    /// 0 to keep such responses until they expire from inactivity.
+    pub default_ttl_secs: u64,
+    /// derive the freshness lifetime of responses without explicit freshness from their Last-Modified header.
+    /// the lifetime is 10% of the time since the last modification.
+    pub heuristic_freshness: bool,
+    /// in seconds, maximum freshness lifetime given by the heuristic.
+    pub heuristic_max_ttl_secs: u64,
+    /// client addresses allowed to override the freshness lifetime of a new entry with the X-Cache-TTL request header.
+    pub ttl_override_allowlist: Vec<IpAddr>,
+    /// in seconds, entries fetched longer ago are revalidated with the backend service before being served, whatever their freshness.
+    /// 0 to disable.
+    pub max_entry_age_secs: u64,
+    /// request headers always part of the cache key, as if the backend service had listed them in Vary.
+    pub always_vary_on: Vec<String>,
+    /// maximum number of variations of the same request kept in cache, the oldest are evicted first. 0 for no limit.
+    pub max_variants_per_key: usize,
+    /// status codes of the responses that can be cached, other responses are passed through to the client without being stored.
+    pub cacheable_statuses: Vec<u16>,
+    /// store the bodies compressed with brotli to fit more entries in size_limit, at the cost of CPU on every hit.
+    pub compress_bodies: bool,
+    /// in bytes, bodies of this size or smaller are never compressed.
+    pub compress_min_bytes: u64,
+    /// in seconds after the freshness lifetime, a stale entry is served instead of an error of the backend service, with a Warning header.
+    /// 0 to return the error.
+    pub stale_if_error_secs: u64,
+    /// cache the responses with a Set-Cookie header, the cookie of a user is then given to every client of the entry.
+    pub cache_set_cookie: bool,
+    /// use the cache for requests with an Authorization header, their responses can be specific to the user.
+    pub cache_authorized_requests: bool,
+    /// file where the cache is saved on shutdown and loaded from on startup, none to start with an empty cache.
+    pub persist_path: Option<PathBuf>,
+}
+
+impl CacheConfig {
+    /// true if the method is configured to bypass the cache.
+    pub fn is_non_cacheable_method(&self, method: &Method) -> bool {
+        self.non_cacheable_methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+    }
+    /// true if a response with this status can be stored.
+    pub fn is_cacheable_status(&self, status: StatusCode) -> bool {
+        self.cacheable_statuses.contains(&status.as_u16())
+    }
+    /// time after the freshness lifetime a stale entry can replace an error of the backend service, none if disabled.
+    pub fn stale_if_error(&self) -> Option<Duration> {
+        (self.stale_if_error_secs > 0).then(|| Duration::from_secs(self.stale_if_error_secs))
+    }
+    /// maximum age of an entry served without revalidation, none if disabled.
+    pub fn max_entry_age(&self) -> Option<Duration> {
+        (self.max_entry_age_secs > 0).then(|| Duration::from_secs(self.max_entry_age_secs))
+    }
+    /// names of the headers always part of the cache key, invalid names are ignored.
+    pub fn always_vary_on(&self) -> Vec<HeaderName> {
+        self.always_vary_on
+            .iter()
+            .filter_map(|name| HeaderName::from_str(name).ok())
+            .collect()
+    }
+    /// true if the client can override the freshness lifetime of the entries it creates.
+    pub fn can_override_ttl(&self, client: Option<IpAddr>) -> bool {
+        client.is_some_and(|ip| self.ttl_override_allowlist.contains(&ip))
+    }
 }
 
 /// About a month to clear unused entries (if there still room)
 /// 250Mb of memory space for the cache
+/// 10Mb maximum for a cached body
 impl Default for CacheConfig {
     fn default() -> Self {
         Self {
+            name: "mnemosyne".to_string(),
             expiration: 300,
             size_limit: 250,
+            max_cacheable_body_bytes: 10 * 1024 * 1024,
+            non_cacheable_methods: Vec::new(),
+            default_ttl_secs: 0,
+            heuristic_freshness: false,
+            heuristic_max_ttl_secs: 86400,
+            ttl_override_allowlist: Vec::new(),
+            max_entry_age_secs: 0,
+            always_vary_on: Vec::new(),
+            max_variants_per_key: 0,
+            cacheable_statuses: vec![200, 203, 204, 300, 301, 404, 410],
+            compress_bodies: false,
+            compress_min_bytes: 1024,
+            stale_if_error_secs: 0,
+            cache_set_cookie: false,
+            cache_authorized_requests: false,
+            persist_path: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{uri::PathAndQuery, HeaderValue};
+    use url::Url;
+
+    use std::path::PathBuf;
+
+    use super::{
+        config_path, Backend, Balance, BodyRewrite, CacheOverride, Config, Endpoint, Listener,
+        DEFAULT_CONFIG_PATH,
+    };
+
+    fn backend_uri(backend: &str, uri_req: &'static str) -> String {
+        let config = Config::builder()
+            .endpoint("example.com", Url::parse(backend).unwrap())
+            .build();
+        config
+            .to_backend_uri(
+                &PathAndQuery::from_static(uri_req),
+                &Some(HeaderValue::from_static("example.com")),
+            )
+            .to_string()
+    }
+    #[test]
+    fn backend_with_path_prefix() {
+        assert_eq!(
+            backend_uri("http://backend/v1/", "/abc"),
+            "http://backend/v1/abc"
+        );
+        assert_eq!(
+            backend_uri("http://backend/v1", "/abc"),
+            "http://backend/v1/abc"
+        );
+        assert_eq!(
+            backend_uri("http://backend/v1/", "/abc/def?a=1"),
+            "http://backend/v1/abc/def?a=1"
+        );
+        assert_eq!(backend_uri("http://backend/v1/", "/"), "http://backend/v1/");
+    }
+    #[test]
+    fn backend_url_boundary() {
+        // only the slashes between the backend path and the request path are collapsed
+        assert_eq!(backend_uri("http://backend", "/abc"), "http://backend/abc");
+        assert_eq!(backend_uri("http://backend/", "/abc"), "http://backend/abc");
+        assert_eq!(
+            backend_uri("https://backend:8080/", "/abc//def"),
+            "https://backend:8080/abc//def"
+        );
+        // query strings and encoded characters are kept as sent
+        assert_eq!(
+            backend_uri("http://backend/", "/a%20b/c%2Fd?q=a%26b&url=http://x"),
+            "http://backend/a%20b/c%2Fd?q=a%26b&url=http://x"
+        );
+    }
+    #[test]
+    fn host_patterns() {
+        let url = |port: u16| Url::parse(&format!("http://127.0.0.1:{port}")).unwrap();
+        let config = Config::builder()
+            .endpoint("*.example.com", url(1))
+            .endpoint("api.example.com", url(2))
+            .endpoint("~^(www\\.)?example\\.(net|org)$", url(3))
+            .fallback(url(4))
+            .build();
+        let backend = |host: &'static str| {
+            config
+                .to_backend_uri(
+                    &PathAndQuery::from_static("/"),
+                    &Some(HeaderValue::from_static(host)),
+                )
+                .port()
+                .unwrap()
+        };
+        // exact match preferred over the pattern listed first
+        assert_eq!(backend("api.example.com"), 2);
+        assert_eq!(backend("API.Example.com"), 2);
+        assert_eq!(backend("static.example.com"), 1);
+        assert_eq!(backend("a.b.EXAMPLE.com"), 1);
+        assert_eq!(backend("www.example.org"), 3);
+        assert_eq!(backend("example.net"), 3);
+        // nothing matches, fallback
+        assert_eq!(backend("example.com"), 4);
+        assert_eq!(backend("example.org.evil.com"), 4);
+        // an invalid pattern is refused when loading the configuration
+        assert!(toml::from_str::<Config>(r#"endpoints = [["~(", "http://127.0.0.1:1"]]"#).is_err());
+    }
+    #[test]
+    fn self_referential_fallback() {
+        let config = Config::builder()
+            .listen_address("127.0.0.1:9830".parse().unwrap())
+            .fallback(Url::parse("http://localhost:9830").unwrap())
+            .build();
+        assert!(config.check_loop().is_err());
+        let config = Config::builder()
+            .listen_address("0.0.0.0:9830".parse().unwrap())
+            .endpoint(
+                "example.com",
+                Url::parse("http://127.0.0.1:9830/v1").unwrap(),
+            )
+            .build();
+        assert!(config.check_loop().is_err());
+        let config = Config::builder()
+            .listen_address("127.0.0.1:9830".parse().unwrap())
+            .endpoint("example.com", Url::parse("http://127.0.0.1:9831").unwrap())
+            .fallback(Url::parse("http://example.net").unwrap())
+            .build();
+        assert!(config.check_loop().is_ok());
+    }
+    #[test]
+    fn weighted_backends() {
+        let stable = Url::parse("http://stable").unwrap();
+        let canary = Url::parse("http://canary").unwrap();
+        let endpoint = Endpoint {
+            host: "example.com".to_string(),
+            backends: vec![
+                Backend {
+                    url: stable.clone(),
+                    weight: 95,
+                },
+                Backend {
+                    url: canary.clone(),
+                    weight: 5,
+                },
+            ],
+            balance: Balance::default(),
+            rewrite: BodyRewrite::default(),
+            client_cache_control: None,
+            cache: CacheOverride::default(),
+            prefix: None,
+            preserve_host: true,
+            host_header: None,
+            matcher: None,
+            next: Default::default(),
+        };
+        let picks = 10000;
+        let to_canary = (0..picks)
+            .filter(|_| endpoint.pick_backend(|_| true) == Some(&canary))
+            .count();
+        // 5% expected, with some tolerance for randomness
+        assert!(
+            (300..700).contains(&to_canary),
+            "{to_canary} requests to canary"
+        );
+    }
+    #[test]
+    fn endpoints_formats() {
+        let config: Config = toml::from_str(
+            r#"
+            endpoints = [
+                ["example.net", "http://127.0.0.1:9934"],
+                { host = "example.com", backends = [{ url = "http://127.0.0.1:9935", weight = 9 }, { url = "http://127.0.0.1:9936" }] },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.endpoints[0].host, "example.net");
+        assert_eq!(config.endpoints[0].backends[0].weight, 1);
+        assert_eq!(config.endpoints[1].backends[0].weight, 9);
+        assert_eq!(config.endpoints[1].backends[1].weight, 1);
+    }
+    #[test]
+    fn prefix_routing() {
+        let url = |port: u16| Url::parse(&format!("http://127.0.0.1:{port}")).unwrap();
+        let prefixed = |host: &str, prefix: &str, port: u16| {
+            let mut endpoint = Endpoint::new(host, url(port));
+            endpoint.prefix = Some(prefix.to_string());
+            endpoint
+        };
+        let config = Config::builder()
+            .endpoint("example.com", url(1))
+            .with_endpoint(prefixed("example.com", "/api/v2", 3))
+            .with_endpoint(prefixed("example.com", "/api/", 2))
+            .with_endpoint(prefixed("*.example.com", "/static", 4))
+            .fallback(url(5))
+            .build();
+        let backend = |host: &'static str, path: &'static str| {
+            let url = config.to_backend_uri(
+                &PathAndQuery::from_static(path),
+                &Some(HeaderValue::from_static(host)),
+            );
+            (
+                url.port().unwrap(),
+                url[url::Position::BeforePath..].to_string(),
+            )
+        };
+        assert_eq!(backend("example.com", "/"), (1, "/".to_string()));
+        // the prefix is removed from the path sent to the backend service
+        assert_eq!(backend("example.com", "/api/abc"), (2, "/abc".to_string()));
+        assert_eq!(backend("example.com", "/api?a=1"), (2, "/?a=1".to_string()));
+        // only whole segments match
+        assert_eq!(backend("example.com", "/apis"), (1, "/apis".to_string()));
+        // longest prefix preferred, whatever the order of the endpoints
+        assert_eq!(
+            backend("example.com", "/api/v2/abc?a=1"),
+            (3, "/abc?a=1".to_string())
+        );
+        assert_eq!(
+            backend("cdn.example.com", "/static/img.png"),
+            (4, "/img.png".to_string())
+        );
+        // the exact HOST is preferred over a pattern, even with a matching prefix
+        assert_eq!(
+            backend("example.com", "/static/img.png"),
+            (1, "/static/img.png".to_string())
+        );
+        // no prefix of the HOST matches, fallback
+        assert_eq!(
+            backend("cdn.example.com", "/other"),
+            (5, "/other".to_string())
+        );
+    }
+    #[test]
+    fn validate() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        let valid = Config::builder()
+            .endpoint("example.com", url("http://127.0.0.1:9934"))
+            .endpoint("*.example.org", url("https://backend.internal/v1"))
+            .fallback(url("http://127.0.0.1:1000"))
+            .build();
+        assert!(valid.validate().is_ok());
+        assert!(Config::default().validate().is_ok());
+        let invalid = |change: fn(&mut Config)| {
+            let mut config = valid.clone();
+            change(&mut config);
+            config.validate().unwrap_err().to_string()
+        };
+        assert!(
+            invalid(|c| c.fall_back_endpoint = Url::parse("ftp://example.com").unwrap())
+                .contains("fallback")
+        );
+        assert!(invalid(
+            |c| c.endpoints[0].backends[0].url = Url::parse("unix:/run/backend.sock").unwrap()
+        )
+        .contains("invalid backend url"));
+        assert!(invalid(|c| c.endpoints[0].backends[0].weight = 0).contains("weight"));
+        assert!(invalid(|c| c.endpoints[1].host = "~(".to_string()).contains("invalid pattern"));
+        assert!(invalid(|c| c.endpoints[0].host = String::new()).contains("without HOST"));
+        assert!(invalid(|c| c.endpoints[0].prefix = Some("api".to_string())).contains("prefix"));
+        assert!(
+            invalid(|c| c.endpoints[0].host_header = Some("a\nb".to_string()))
+                .contains("host_header")
+        );
+        assert!(invalid(|c| c.default_host = Some("a\nb".to_string())).contains("default_host"));
+        assert!(invalid(|c| c.admin_token = Some(" ".to_string())).contains("admin_token"));
+        assert!(invalid(|c| c.listeners = vec![Listener {
+            address: c.listen_address,
+            fallback: false,
+        }])
+        .contains("twice"));
+        // a loop is refused too
+        assert!(
+            invalid(|c| c.fall_back_endpoint = Url::parse("http://127.0.0.1:9830").unwrap())
+                .contains("loop")
+        );
+    }
+    #[test]
+    fn config_file_path() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let env = || Some("/env/config.toml".to_string());
+        assert_eq!(
+            config_path(args(&[]), None).unwrap(),
+            PathBuf::from(DEFAULT_CONFIG_PATH)
+        );
+        assert_eq!(
+            config_path(args(&[]), env()).unwrap(),
+            PathBuf::from("/env/config.toml")
+        );
+        // the argument takes precedence over the environment
+        for given in [
+            &["--config", "/arg.toml"][..],
+            &["--config=/arg.toml"],
+            &["-c", "/arg.toml"],
+        ] {
+            assert_eq!(
+                config_path(args(given), env()).unwrap(),
+                PathBuf::from("/arg.toml")
+            );
         }
+        assert!(config_path(args(&["--config"]), None).is_err());
+        assert!(config_path(args(&["--verbose"]), None).is_err());
+        // a configuration is loaded from the path given
+        let path = std::env::temp_dir().join(format!("mnemosyne-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "listen_address = \"127.0.0.1:9999\"\n").unwrap();
+        let given = config_path(args(&["--config", path.to_str().unwrap()]), env()).unwrap();
+        let config = confy::load_path::<Config>(&given).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.listen_address, "127.0.0.1:9999".parse().unwrap());
+    }
+    #[test]
+    fn listeners_as_addresses() {
+        let config: Config = toml::from_str(
+            r#"listeners = ["[::1]:9831", { address = "0.0.0.0:9832", fallback = false }]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listeners,
+            vec![
+                Listener {
+                    address: "[::1]:9831".parse().unwrap(),
+                    fallback: true,
+                },
+                Listener {
+                    address: "0.0.0.0:9832".parse().unwrap(),
+                    fallback: false,
+                },
+            ]
+        );
+        assert_eq!(config.all_listeners().len(), 3);
+    }
+    #[test]
+    fn backend_without_path() {
+        assert_eq!(backend_uri("http://backend", "/abc"), "http://backend/abc");
+        assert_eq!(
+            backend_uri("http://backend:8080/", "/"),
+            "http://backend:8080/"
+        );
     }
 }
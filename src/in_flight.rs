@@ -0,0 +1,55 @@
+use std::sync::{Arc, Mutex};
+
+use ahash::HashMap;
+use axum::http::uri::PathAndQuery;
+use axum::http::{HeaderName, HeaderValue};
+use reqwest::Method;
+use tokio::sync::{OwnedRwLockWriteGuard, RwLock};
+
+/// signature of a request fetched from a backend service, with the values of the headers its response can vary on.
+type Key = (
+    Method,
+    PathAndQuery,
+    HeaderValue,
+    Vec<(HeaderName, HeaderValue)>,
+);
+
+/// requests being fetched from the backend services.
+/// Concurrent misses for the same request wait for the first fetch instead of all reaching the backend service.
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<Mutex<HashMap<Key, Arc<RwLock<()>>>>>);
+
+/// held by the request fetching the response, the waiting requests are released when it is dropped.
+/// Must be kept until the response is in the cache.
+pub struct FetchGuard {
+    in_flight: InFlight,
+    key: Key,
+    _lock: OwnedRwLockWriteGuard<()>,
+}
+
+impl InFlight {
+    /// register a fetch for a request.
+    /// Will return the guard of the fetch if none is in flight for this request,
+    /// or the lock to wait for the fetch in flight otherwise.
+    pub fn join(&self, key: Key) -> Result<FetchGuard, Arc<RwLock<()>>> {
+        let mut map = self.0.lock().unwrap();
+        if let Some(lock) = map.get(&key) {
+            return Err(lock.clone());
+        }
+        let lock = Arc::new(RwLock::new(()));
+        // never contended, the lock is not shared yet.
+        let guard = lock.clone().try_write_owned().unwrap();
+        map.insert(key.clone(), lock);
+        Ok(FetchGuard {
+            in_flight: self.clone(),
+            key,
+            _lock: guard,
+        })
+    }
+}
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        self.in_flight.0.lock().unwrap().remove(&self.key);
+    }
+}
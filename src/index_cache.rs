@@ -1,52 +1,124 @@
-use ahash::HashMap;
-use ahash::HashMapExt;
 use axum::body::Body;
 use axum::http::uri::PathAndQuery;
-use axum::http::HeaderValue;
 use axum::http::{HeaderMap, Request};
-use derive_more::{Deref, DerefMut};
+use axum::http::{HeaderName, HeaderValue};
+use dashmap::DashMap;
+use derive_more::Deref;
 use reqwest::header::HOST;
 use reqwest::Method;
 use uuid::Uuid;
-#[derive(Deref, DerefMut, Clone, Debug)]
+/// Surrogate-Key header, tags of a response to purge it with other responses.
+pub const SURROGATE_KEY: HeaderName = HeaderName::from_static("surrogate-key");
+
+/// signature of a request: its method, path and query, and HOST.
+pub type Signature = (axum::http::Method, PathAndQuery, HeaderValue);
+
+#[derive(Deref, Clone, Debug)]
 /// IndexCache will store entry for each combination of method/uri/host with a vec of uuid per HeaderMap. HeaderMap here are request headers that match the headers name in the Vary header value response.
+/// The map is sharded, requests for different signatures do not wait for each other.
+/// A reference returned by `get` locks its shard, it must be dropped before modifying the index.
+/// Entries can also be tagged, to be deleted together whatever their signature.
+/// The signature of every entry is kept too, an entry is removed without looking through every signature.
 pub struct IndexCache(
-    pub HashMap<(axum::http::Method, PathAndQuery, HeaderValue), Vec<(Uuid, HeaderMap)>>,
+    #[deref] pub DashMap<Signature, Vec<(Uuid, HeaderMap)>, ahash::RandomState>,
+    TagIndex,
+    DashMap<Uuid, Signature, ahash::RandomState>,
 );
 
+/// entries of each tag given by the backend services in the Surrogate-Key header, and the other way around.
+#[derive(Clone, Debug, Default)]
+struct TagIndex {
+    entries: DashMap<String, Vec<Uuid>, ahash::RandomState>,
+    tags: DashMap<Uuid, Vec<String>, ahash::RandomState>,
+}
+
+impl TagIndex {
+    /// forget the tags of an entry.
+    fn untag(&self, uuid: &Uuid) {
+        let Some((_, tags)) = self.tags.remove(uuid) else {
+            return;
+        };
+        for tag in tags {
+            self.entries.remove_if_mut(&tag, |_, entries| {
+                entries.retain(|e| e != uuid);
+                entries.is_empty()
+            });
+        }
+    }
+}
+
+impl Default for IndexCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl IndexCache {
     pub fn new() -> Self {
-        IndexCache(HashMap::new())
+        IndexCache(DashMap::default(), TagIndex::default(), DashMap::default())
     }
+    /// add an entry to the index.
+    /// An entry with the same signature and the same headers is replaced.
+    /// If the signature has more than max_variants entries, the oldest are removed. 0 for no limit.
+    /// Will return the uuids removed from the index, so they can be invalidated in the cache.
     pub fn add_entry(
-        &mut self,
+        &self,
         uuid: Uuid,
         req_method: Method,
         req_uri: PathAndQuery,
         req_host: HeaderValue,
         req_headers_match_vary: HeaderMap,
-    ) {
+        max_variants: usize,
+    ) -> Vec<Uuid> {
         let key = (req_method, req_uri, req_host);
-        let value = (uuid, req_headers_match_vary);
-        // check if entry exist for method/uri
-
-        if let Some(v) = self.get_mut(&key) {
-            // if entry exist, push into vec
-            v.push(value);
-        } else {
-            // if no entries, create one.
-            self.insert(key, vec![value]);
-        }
+        let removed = {
+            // the entry locks the shard of the signature until the variants are updated.
+            let mut v = self.entry(key.clone()).or_default();
+            if let Some(existing) = v.iter_mut().find(|e| e.1 == req_headers_match_vary) {
+                // if the same variation exists, replace it
+                vec![std::mem::replace(&mut existing.0, uuid)]
+            } else {
+                v.push((uuid, req_headers_match_vary));
+                // variants are pushed in order, the oldest are first.
+                let excess = match max_variants {
+                    0 => 0,
+                    max => v.len().saturating_sub(max),
+                };
+                v.drain(..excess).map(|e| e.0).collect()
+            }
+        };
+        removed.iter().for_each(|uuid| {
+            self.1.untag(uuid);
+            self.2.remove(uuid);
+        });
+        self.2.insert(uuid, key);
+        removed
     }
     /// will search for an entry in cache based on a request. Will check that request headers includes the ones associated in this entry if any.
+    /// The headers of always_vary_on must have the same value in the request and the entry, or be absent from both.
+    /// A HEAD request resolves the entry of the GET request, its headers can be reused.
+    /// With normalize_query, the query is looked up in its canonical order, see [`normalize_query`].
     /// Will return the uuid of the entry.
-    pub fn request_to_uuid(&self, request: &Request<Body>) -> Option<Uuid> {
-        let method = request.method().to_owned();
+    pub fn request_to_uuid(
+        &self,
+        request: &Request<Body>,
+        always_vary_on: &[HeaderName],
+        normalize_query: bool,
+    ) -> Option<Uuid> {
+        let method = match request.method() {
+            &Method::HEAD => Method::GET,
+            method => method.to_owned(),
+        };
         let uri = request
             .uri()
             .path_and_query()
             .cloned()
-            .unwrap_or(PathAndQuery::from_static(""));
+            .unwrap_or_else(|| PathAndQuery::from_static("/"));
+        let uri = if normalize_query {
+            self::normalize_query(&uri)
+        } else {
+            uri
+        };
         let host = request.headers().get(HOST)?;
         let headermap = request.headers();
         if let Some(uuids) = self.get(&(method, uri.clone(), host.clone())) {
@@ -56,43 +128,330 @@ impl IndexCache {
                     headermap_object
                         .iter()
                         .all(|x| headermap.get(x.0).is_some_and(|value| value == x.1))
+                        && always_vary_on
+                            .iter()
+                            .all(|name| headermap.get(name) == headermap_object.get(name))
                 })
                 .map(|v| v.0);
         }
         None
     }
-    pub fn delete_uuid_from_index(&mut self, uuid: &Uuid) {
-        // remove uuid entry from vec
-        self.iter_mut().for_each(|v| v.1.retain(|c| &c.0 != uuid));
-        // check if the entry for method/uri is now empty and delete it if that's the case.
-        let key = self.iter().find_map(|(key, value)| {
-            if value.is_empty() {
-                Some(key.to_owned())
-            } else {
-                None
+    /// names of the headers the entries of a signature vary on.
+    pub fn vary_names(&self, signature: &Signature) -> Vec<HeaderName> {
+        let Some(entries) = self.get(signature) else {
+            return vec![];
+        };
+        entries
+            .iter()
+            .flat_map(|(_, headers)| headers.keys().cloned())
+            .collect()
+    }
+    /// remove every entry of the HOSTs matched from the index.
+    /// Will return the uuids of the removed entries.
+    pub fn delete_host(&self, matches: impl Fn(&str) -> bool) -> Vec<Uuid> {
+        let mut uuids = vec![];
+        self.retain(|(_, _, h), entries| {
+            if !h.to_str().is_ok_and(&matches) {
+                return true;
             }
+            uuids.extend(entries.iter().map(|e| e.0));
+            false
+        });
+        uuids.iter().for_each(|uuid| {
+            self.1.untag(uuid);
+            self.2.remove(uuid);
         });
-        if let Some(key) = key {
-            self.remove(&key);
+        uuids
+    }
+    /// remove every entry of a signature from the index.
+    /// Will return the removed entries.
+    pub fn delete_signature(&self, signature: &Signature) -> Option<Vec<(Uuid, HeaderMap)>> {
+        let (_, entries) = self.remove(signature)?;
+        entries.iter().for_each(|(uuid, _)| {
+            self.1.untag(uuid);
+            self.2.remove(uuid);
+        });
+        Some(entries)
+    }
+    /// tag an entry, in addition to the tags it already has.
+    pub fn tag_entry(&self, uuid: Uuid, tags: Vec<String>) {
+        for tag in &tags {
+            self.1.entries.entry(tag.clone()).or_default().push(uuid);
+        }
+        self.1.tags.entry(uuid).or_default().extend(tags);
+    }
+    /// remove every entry with this tag from the index.
+    /// Will return the uuids of the removed entries.
+    pub fn delete_tag(&self, tag: &str) -> Vec<Uuid> {
+        let Some((_, uuids)) = self.1.entries.remove(tag) else {
+            return vec![];
+        };
+        uuids
+            .iter()
+            .for_each(|uuid| self.delete_uuid_from_index(uuid));
+        uuids
+    }
+    /// forget the tags of an entry removed from the cache.
+    pub fn untag_entry(&self, uuid: &Uuid) {
+        self.1.untag(uuid);
+    }
+    /// remove every entry and tag from the index.
+    pub fn clear(&self) {
+        self.0.clear();
+        self.1.entries.clear();
+        self.1.tags.clear();
+        self.2.clear();
+    }
+    /// remove an entry from the index, found through its signature.
+    /// The signature is removed if it is left without entries.
+    pub fn delete_uuid_from_index(&self, uuid: &Uuid) {
+        if let Some((_, signature)) = self.2.remove(uuid) {
+            self.remove_if_mut(&signature, |_, entries| {
+                entries.retain(|c| &c.0 != uuid);
+                entries.is_empty()
+            });
         }
+        self.1.untag(uuid);
     }
 }
-/// from a request, keep only headers that are present in Vary response header
-pub fn headers_match_vary(
+/// path with the parameters of its query sorted by name then value, the canonical form used in the cache key.
+/// Empty parameters are dropped, `?b=2&a=1&` becomes `?a=1&b=2`.
+pub fn normalize_query(path: &PathAndQuery) -> PathAndQuery {
+    let Some(query) = path.query() else {
+        return path.clone();
+    };
+    let mut params: Vec<_> = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| p.split_once('=').unwrap_or((p, "")))
+        .collect();
+    params.sort();
+    let query = params
+        .iter()
+        .map(|(name, value)| {
+            if value.is_empty() {
+                name.to_string()
+            } else {
+                format!("{name}={value}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let normalized = if query.is_empty() {
+        path.path().to_string()
+    } else {
+        format!("{}?{query}", path.path())
+    };
+    PathAndQuery::from_maybe_shared(normalized).unwrap_or_else(|_| path.clone())
+}
+
+/// tags of a response from its Surrogate-Key headers, separated by spaces.
+pub fn surrogate_keys(headers: &HeaderMap) -> Vec<String> {
+    let mut tags: Vec<String> = headers
+        .get_all(SURROGATE_KEY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(str::split_whitespace)
+        .map(str::to_string)
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// from a request, keep only headers that are present in the Vary response headers
+/// the field names of every Vary header are united, header names are case insensitive and the spaces around them are ignored.
+pub fn headers_match_vary<'a>(
     request_headers: &HeaderMap,
-    vary_header: Option<&HeaderValue>,
+    vary_headers: impl IntoIterator<Item = &'a HeaderValue>,
 ) -> anyhow::Result<HeaderMap> {
-    if let Some(vary) = vary_header {
-        let mut h_vary = vary.to_str()?.split(',');
+    let mut h_vary = Vec::new();
+    for vary in vary_headers {
+        h_vary.extend(vary.to_str()?.split(',').map(str::trim));
+    }
+    let mut headers = HeaderMap::new();
+    request_headers
+        .iter()
+        .filter(|h_req| {
+            h_vary
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(h_req.0.as_str()))
+        })
+        .for_each(|header| {
+            headers.insert(header.0, header.1.clone());
+        });
+    Ok(headers)
+}
+
+/// true if the response varies on `*` in any of its Vary headers, it depends on more than the request headers and can not be reused.
+pub fn vary_any<'a>(vary_headers: impl IntoIterator<Item = &'a HeaderValue>) -> bool {
+    vary_headers
+        .into_iter()
+        .filter_map(|vary| vary.to_str().ok())
+        .any(|vary| vary.split(',').any(|name| name.trim() == "*"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use axum::body::Body;
+    use axum::http::{uri::PathAndQuery, HeaderMap, HeaderValue, Method, Request};
+    use reqwest::header::HOST;
+    use uuid::Uuid;
+
+    use super::{headers_match_vary, normalize_query, surrogate_keys, vary_any, IndexCache};
+
+    #[test]
+    fn duplicate_entries_collapsed() {
+        let index = IndexCache::new();
+        let key = (
+            Method::GET,
+            PathAndQuery::from_static("/"),
+            HeaderValue::from_static("example.com"),
+        );
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let add = |index: &IndexCache, uuid| {
+            index.add_entry(
+                uuid,
+                key.0.clone(),
+                key.1.clone(),
+                key.2.clone(),
+                HeaderMap::new(),
+                0,
+            )
+        };
+        assert!(add(&index, first).is_empty());
+        // the same request cached again replaces the previous entry
+        assert_eq!(add(&index, second), vec![first]);
+        let entries = index.get(&key).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, second);
+    }
+    #[test]
+    fn concurrent_access() {
+        let index = IndexCache::new();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let index = &index;
+                scope.spawn(move || {
+                    for i in 0..200 {
+                        let path = format!("/{thread}/{i}");
+                        let uuid = Uuid::new_v4();
+                        index.add_entry(
+                            uuid,
+                            Method::GET,
+                            PathAndQuery::from_str(&path).unwrap(),
+                            HeaderValue::from_static("example.com"),
+                            HeaderMap::new(),
+                            0,
+                        );
+                        let request = Request::get(&path)
+                            .header(HOST, "example.com")
+                            .body(Body::empty())
+                            .unwrap();
+                        assert_eq!(index.request_to_uuid(&request, &[], false), Some(uuid));
+                        if i % 2 == 0 {
+                            index.delete_uuid_from_index(&uuid);
+                            assert_eq!(index.request_to_uuid(&request, &[], false), None);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(index.len(), 8 * 100);
+    }
+    #[test]
+    fn tags_follow_entries() {
+        let index = IndexCache::new();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        for (uuid, path) in [(first, "/first"), (second, "/second")] {
+            index.add_entry(
+                uuid,
+                Method::GET,
+                PathAndQuery::from_static(path),
+                HeaderValue::from_static("example.com"),
+                HeaderMap::new(),
+                0,
+            );
+        }
         let mut headers = HeaderMap::new();
-        request_headers
-            .iter()
-            .filter(|h_req| h_vary.any(|name| name == h_req.0.as_str()))
-            .for_each(|header| {
-                headers.insert(header.0, header.1.clone());
-            });
-        Ok(headers)
-    } else {
-        Ok(HeaderMap::new())
+        headers.append("surrogate-key", HeaderValue::from_static("news  sport"));
+        headers.append("surrogate-key", HeaderValue::from_static("news"));
+        assert_eq!(surrogate_keys(&headers), vec!["news", "sport"]);
+        index.tag_entry(first, surrogate_keys(&headers));
+        index.tag_entry(second, vec!["news".to_string()]);
+        // a deleted entry is removed from its tags
+        index.delete_uuid_from_index(&first);
+        assert!(index.delete_tag("sport").is_empty());
+        assert_eq!(index.delete_tag("news"), vec![second]);
+        assert!(index.is_empty());
+        assert!(index.2.is_empty());
+    }
+    #[test]
+    fn query_normalized() {
+        let normalized = |path| normalize_query(&PathAndQuery::from_static(path));
+        assert_eq!(normalized("/?b=2&a=1"), "/?a=1&b=2");
+        assert_eq!(normalized("/?a=2&b=1&a=1"), "/?a=1&a=2&b=1");
+        assert_eq!(normalized("/path?flag&a=1&"), "/path?a=1&flag");
+        assert_eq!(normalized("/path"), "/path");
+        assert_eq!(normalized("/path?"), "/path");
+        let index = IndexCache::new();
+        let uuid = Uuid::new_v4();
+        index.add_entry(
+            uuid,
+            Method::GET,
+            normalized("/?b=2&a=1"),
+            HeaderValue::from_static("example.com"),
+            HeaderMap::new(),
+            0,
+        );
+        let request = Request::get("/?b=2&a=1")
+            .header(HOST, "example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(index.request_to_uuid(&request, &[], true), Some(uuid));
+        assert_eq!(index.request_to_uuid(&request, &[], false), None);
+    }
+    #[test]
+    fn vary_case_insensitive() {
+        let mut request = HeaderMap::new();
+        request.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        request.insert("accept-language", HeaderValue::from_static("fr"));
+        request.insert("user-agent", HeaderValue::from_static("test"));
+        let vary = HeaderValue::from_static("Accept-Language,ACCEPT-ENCODING");
+        let headers = headers_match_vary(&request, Some(&vary)).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["accept-encoding"], "gzip");
+        assert_eq!(headers["accept-language"], "fr");
+        assert!(vary_any(Some(&HeaderValue::from_static("*"))));
+        assert!(!vary_any(Some(&vary)));
+    }
+    #[test]
+    fn multiple_vary_headers() {
+        let mut request = HeaderMap::new();
+        request.insert("accept-language", HeaderValue::from_static("fr"));
+        request.insert("accept-encoding", HeaderValue::from_static("br"));
+        request.insert("user-agent", HeaderValue::from_static("curl"));
+        let mut response = HeaderMap::new();
+        response.append("vary", HeaderValue::from_static("Accept-Language"));
+        response.append("vary", HeaderValue::from_static("Accept-Encoding"));
+        let headers = headers_match_vary(&request, response.get_all("vary")).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.get("accept-language").unwrap(), "fr");
+        assert_eq!(headers.get("accept-encoding").unwrap(), "br");
+        assert!(!vary_any(response.get_all("vary")));
+        response.append("vary", HeaderValue::from_static("*"));
+        assert!(vary_any(response.get_all("vary")));
+    }
+    #[test]
+    fn vary_with_spaces() {
+        let mut request = HeaderMap::new();
+        request.insert("accept", HeaderValue::from_static("text/html"));
+        request.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        let vary = HeaderValue::from_static("Accept, Accept-Encoding");
+        let headers = headers_match_vary(&request, Some(&vary)).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["accept-encoding"], "gzip");
     }
 }
@@ -0,0 +1,4142 @@
+#![doc = include_str!("../doc/README.md")]
+use aide::axum::routing::{delete, get, post, put};
+use aide::axum::ApiRouter;
+use aide::openapi::OpenApi;
+use anyhow::Context;
+use api::access_log::access_log;
+use api::auth::admin_auth;
+use api::cache::{
+    archive_cache, cache_stats, delete_entries, delete_entries_per_path,
+    delete_entries_per_signature, delete_entries_per_tag, delete_entry_per_uuid, export_cache,
+    get_cache_entry, get_cache_entry_metadata, import_cache, import_entries, list_cache_entries,
+    prime_entry, warm_cache, RecentPurges,
+};
+use api::config::{
+    add_endpoint, delete_endpoint, delete_endpoints, get_endpoints, get_expiration,
+    get_fallback_value, preview_route, set_expiration, set_fallback_value, set_maintenance,
+};
+use api::health::{health, ready};
+use api::metrics::{metrics, Metrics};
+use archive::ArchivedEntry;
+use axum::http::HeaderValue;
+use axum::middleware::from_fn_with_state;
+use axum::{Extension, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use cache::Cache;
+use circuit_breaker::CircuitBreakers;
+use config::{Config, TlsConfig};
+use futures_util::StreamExt;
+use in_flight::InFlight;
+use index_cache::IndexCache;
+use reqwest::header::ACCESS_CONTROL_ALLOW_ORIGIN;
+use reqwest::Client;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{RwLock, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tower_http::set_header::SetResponseHeaderLayer;
+use tracing::{info, warn};
+
+use crate::doc::{description_docs, serve_docs};
+
+/// Handlers
+mod api;
+/// portable format of the cache entries for export and import
+mod archive;
+/// impl for Moka Cache wrapper
+pub mod cache;
+/// parsing of Cache-Control directives
+mod cache_control;
+/// circuit breakers of the backend services
+mod circuit_breaker;
+/// configuration from file
+pub mod config;
+/// OpenAPI
+mod doc;
+/// coalescing of identical requests to the backend services
+mod in_flight;
+/// IndexCache
+pub mod index_cache;
+/// shared state of Mnemosyne, created with [`new_state`].
+#[derive(Clone)]
+pub struct AppState {
+    config: Arc<RwLock<Config>>,
+    // option HeaderMap is the header request that needs to be present.
+    // the response will contains a Vary Header in this case.
+    // one method and uri can contain multiple different response based on headers, so we use a Vec per entry since the id of the entry is based on uri and method.
+    cache: Cache,
+    index_cache: Arc<IndexCache>,
+    client: Client,
+    recent_purges: RecentPurges,
+    // cache insertions done after the response was sent, awaited on shutdown.
+    tasks: TaskTracker,
+    // cancelled on shutdown, the upgraded connections still relayed are closed.
+    stopping: CancellationToken,
+    metrics: Arc<Metrics>,
+    // limit of the purges scanning the index at the same time.
+    purge_permits: Arc<Semaphore>,
+    // file the configuration is written back to when modified by the API, none to keep changes in memory only.
+    config_path: Option<Arc<PathBuf>>,
+    // reported by the health endpoint as uptime.
+    started_at: Instant,
+    // requests being fetched from the backend services, identical requests wait for them.
+    in_flight: InFlight,
+    // backend services failing to connect, their requests fail at once.
+    breakers: CircuitBreakers,
+}
+
+impl AppState {
+    /// write the configuration to this file every time it is modified by the API, so changes survive a restart.
+    /// Should be the file the configuration was loaded from.
+    pub fn with_config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(Arc::new(path.into()));
+        self
+    }
+}
+
+/// router of Mnemosyne, containing the admin API, the OpenAPI document and the caching proxy as fallback.
+/// The admin API requires the admin token of the configuration if there is one.
+/// The returned [`Router`] can be served directly, mounted in a larger axum application or used as a `tower::Service`.
+pub fn app_main(state: AppState, mut api: OpenApi) -> Router {
+    ApiRouter::new()
+        .route("/openapi.json", get(serve_docs))
+        .route("/metrics", get(metrics))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .nest(
+            "/api/1",
+            router().layer(from_fn_with_state(state.clone(), admin_auth)),
+        )
+        .fallback(api::handler)
+        .finish_api_with(&mut api, description_docs)
+        .layer(Extension(Arc::new(api)))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("*"),
+        ))
+        .layer(from_fn_with_state(state.clone(), access_log))
+        .with_state(state)
+}
+
+fn router() -> ApiRouter<AppState> {
+    ApiRouter::new()
+        .nest("/cache", cache_router())
+        .nest("/config", config_router())
+}
+
+fn cache_router() -> ApiRouter<AppState> {
+    ApiRouter::new()
+        .api_route("/:uuid", delete(delete_entry_per_uuid))
+        .api_route("/:uuid", get(get_cache_entry))
+        .api_route("/:uuid/metadata", get(get_cache_entry_metadata))
+        .api_route("/path/:path", delete(delete_entries_per_path))
+        .api_route("/tag/:tag", delete(delete_entries_per_tag))
+        .api_route("/lookup", delete(delete_entries_per_signature))
+        .api_route("/export", get(export_cache))
+        .api_route("/import", post(import_cache))
+        .api_route("/warm", post(warm_cache))
+        .api_route("/entries", get(list_cache_entries))
+        .api_route("/", delete(delete_entries))
+        .api_route("/", get(cache_stats))
+        .api_route("/", put(prime_entry))
+}
+fn config_router() -> ApiRouter<AppState> {
+    ApiRouter::new()
+        .api_route("/endpoint/:endpoint", delete(delete_endpoint))
+        .api_route("/endpoint/:endpoint", put(add_endpoint))
+        .api_route("/endpoint", delete(delete_endpoints))
+        .api_route("/endpoint", get(get_endpoints))
+        .api_route("/fallback", get(get_fallback_value))
+        .api_route("/fallback", post(set_fallback_value))
+        .api_route("/cache/expiration", get(get_expiration))
+        .api_route("/cache/expiration", post(set_expiration))
+        .api_route("/maintenance", post(set_maintenance))
+        .api_route("/route", get(preview_route))
+}
+/// create the cache, the index and the http client from a configuration.
+pub fn new_state(config: Config) -> AppState {
+    let purge_permits = match config.max_concurrent_purges {
+        0 => Semaphore::MAX_PERMITS,
+        max => max,
+    };
+    let index_cache = Arc::new(IndexCache::new());
+    AppState {
+        cache: Cache::new(&config, index_cache.clone()),
+        config: Arc::new(RwLock::new(config)),
+        index_cache,
+        client: Client::new(),
+        recent_purges: RecentPurges::default(),
+        tasks: TaskTracker::new(),
+        stopping: CancellationToken::new(),
+        metrics: Arc::default(),
+        purge_permits: Arc::new(Semaphore::new(purge_permits)),
+        config_path: None,
+        started_at: Instant::now(),
+        in_flight: InFlight::default(),
+        breakers: CircuitBreakers::default(),
+    }
+}
+/// wait for the cache insertions still in flight, so the responses just served are not lost.
+/// The upgraded connections still relayed, like WebSocket, are closed.
+/// The cache is then saved to persist_path if configured.
+/// To call after the server stopped accepting requests.
+pub async fn shutdown(state: &AppState) {
+    state.stopping.cancel();
+    state.tasks.close();
+    state.tasks.wait().await;
+    if let Err(e) = save_cache(state).await {
+        warn!("could not save the cache: {e:#}");
+    }
+}
+/// write every entry of the cache to persist_path, does nothing if it is not configured.
+/// The archive is written to a temporary file next to it then renamed, so a previous save is not lost if the write is interrupted.
+pub async fn save_cache(state: &AppState) -> anyhow::Result<()> {
+    let Some(path) = state.config.read().await.cache.persist_path.clone() else {
+        return Ok(());
+    };
+    let archive: Vec<_> = archive_cache(state).await.collect().await;
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, archive.concat())
+        .and_then(|_| std::fs::rename(&tmp, &path))
+        .with_context(|| format!("could not write the cache to {}", path.display()))?;
+    info!("cache saved to {}", path.display());
+    Ok(())
+}
+/// load the entries saved by [`shutdown`] in persist_path, returns the number of entries loaded.
+/// Entries that expired in the meantime are skipped. Nothing is loaded if the file does not exist yet.
+pub async fn load_cache(state: &AppState) -> anyhow::Result<usize> {
+    let Some(path) = state.config.read().await.cache.persist_path.clone() else {
+        return Ok(0);
+    };
+    let archive = match std::fs::read(&path) {
+        Ok(archive) => archive,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("could not read the cache from {}", path.display()))
+        }
+    };
+    let entries = ArchivedEntry::decode_all(archive.into())
+        .with_context(|| format!("{} is not a cache saved by Mnemosyne", path.display()))?;
+    let loaded = import_entries(state, entries).await;
+    info!("{loaded} entries loaded from {}", path.display());
+    Ok(loaded)
+}
+/// TLS configuration of the listen address, from the certificate and key files.
+/// Will return an error if a file is missing or invalid.
+pub async fn load_tls(tls: &TlsConfig) -> anyhow::Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "could not load the TLS certificate {} with the key {}",
+                tls.cert_path.display(),
+                tls.key_path.display()
+            )
+        })
+}
+/// serve the app on a Unix domain socket at path until stop is cancelled.
+/// A stale socket file is removed before binding, and the socket file is removed once the connections in flight are finished.
+/// Will return an error if the path is not a socket or if another process still accepts connections on it.
+#[cfg(unix)]
+pub async fn serve_uds(
+    path: &std::path::Path,
+    app: Router,
+    stop: tokio_util::sync::CancellationToken,
+) -> anyhow::Result<()> {
+    use anyhow::bail;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::{conn::auto, graceful::GracefulShutdown};
+    use hyper_util::service::TowerToHyperService;
+    use std::os::unix::fs::FileTypeExt;
+    use tracing::debug;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            // a socket still accepting connections belongs to a running instance.
+            if tokio::net::UnixStream::connect(path).await.is_ok() {
+                bail!("the socket {} is used by another process", path.display());
+            }
+            std::fs::remove_file(path)
+                .with_context(|| format!("could not remove the socket {}", path.display()))?;
+            info!("removed the stale socket {}", path.display());
+        }
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("could not read {}", path.display())),
+    }
+    let uds = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("could not listen on the socket {}", path.display()))?;
+    let graceful = GracefulShutdown::new();
+    loop {
+        let stream = tokio::select! {
+            accepted = uds.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("could not accept a connection on {}: {e}", path.display());
+                    continue;
+                }
+            },
+            _ = stop.cancelled() => break,
+        };
+        let service = TowerToHyperService::new(app.clone());
+        let builder = auto::Builder::new(TokioExecutor::new());
+        let connection = builder
+            .serve_connection_with_upgrades(TokioIo::new(stream), service)
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("connection on the socket closed: {e}");
+            }
+        });
+    }
+    drop(uds);
+    graceful.shutdown().await;
+    std::fs::remove_file(path)
+        .with_context(|| format!("could not remove the socket {}", path.display()))?;
+    Ok(())
+}
+/// replace the configuration of a running Mnemosyne, for example after the configuration file was edited.
+/// Endpoints, fallback and every setting read per request apply to the next requests.
+/// The addresses and socket to listen on, the TLS certificate, the name and size of the cache, the compression of the bodies
+/// and the maximum of concurrent purges are only read at start, a restart is needed to change them.
+/// A new cache expiration applies to the entries cached from now on.
+/// The maintenance mode is kept as it is, it is toggled with the admin API.
+/// A configuration where requests would loop is refused and the current one is kept.
+pub async fn reload_config(state: &AppState, mut config: Config) -> anyhow::Result<()> {
+    config.validate()?;
+    let mut current = state.config.write().await;
+    if current.all_listeners() != config.all_listeners()
+        || current.listen_uds != config.listen_uds
+        || current.tls != config.tls
+        || current.cache.name != config.cache.name
+        || current.cache.size_limit != config.cache.size_limit
+        || current.cache.compress_bodies != config.cache.compress_bodies
+        || current.cache.compress_min_bytes != config.cache.compress_min_bytes
+        || current.max_concurrent_purges != config.max_concurrent_purges
+    {
+        warn!("listeners, listen_uds, tls, cache name, cache size, body compression and concurrent purges are only applied on restart");
+    }
+    // the maintenance mode set with the admin API is not written to the file, it is not reset by a reload.
+    config.maintenance = current.maintenance;
+    *current = config;
+    info!("configuration reloaded");
+    Ok(())
+}
+// tests
+
+#[cfg(test)]
+// backend
+mod test {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use aide::openapi::OpenApi;
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        http::{HeaderMap, HeaderName, HeaderValue, Uri},
+        response::IntoResponse,
+        routing::{get, post},
+        Extension, Router,
+    };
+    use axum_test::TestServer;
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use futures_util::{future::join_all, stream, SinkExt, StreamExt};
+    use reqwest::{
+        header::{
+            ACCEPT_LANGUAGE, ACCEPT_RANGES, AGE, AUTHORIZATION, CACHE_CONTROL, CONNECTION,
+            CONTENT_LENGTH, CONTENT_RANGE, DATE, ETAG, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+            LAST_MODIFIED, PRAGMA, RANGE, RETRY_AFTER, SET_COOKIE, USER_AGENT, VARY, WARNING,
+            WWW_AUTHENTICATE,
+        },
+        Method, StatusCode,
+    };
+    use std::collections::HashMap;
+    use std::future::IntoFuture;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::{net::TcpListener, spawn, time::sleep};
+    use tokio_tungstenite::tungstenite::Message;
+    use tracing::{
+        field::{Field, Visit},
+        Event, Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        Layer, Registry,
+    };
+    use url::Url;
+    use uuid::Uuid;
+
+    #[cfg(unix)]
+    use crate::serve_uds;
+    use crate::{
+        app_main,
+        config::{
+            AccessLogFormat, Backend, Balance, CacheConfig, Config, Endpoint, Listener,
+            RewriteRule, TlsConfig, UnknownHostStatus,
+        },
+        load_cache, load_tls, new_state, reload_config, shutdown,
+    };
+
+    async fn backend_handler() -> &'static str {
+        "Hello, World!"
+    }
+    fn router_backend() -> Router {
+        Router::new().route("/", get(backend_handler))
+    }
+    // needs to start a backend service, will be assigned an open port by the os
+    async fn app_backend(listener: TcpListener, router: Router) -> Result<()> {
+        axum::serve(listener, router.into_make_service()).await?;
+        Ok(())
+    }
+    // start a backend service and return its url
+    async fn spawn_backend(router: Router) -> Result<Url> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr().unwrap().port();
+        spawn(async move { app_backend(listener, router).await });
+        Ok(Url::parse(&format!("http://127.0.0.1:{port}"))?)
+    }
+    async fn app() -> Result<TestServer> {
+        app_with_backend(router_backend()).await
+    }
+    async fn app_with_backend(router: Router) -> Result<TestServer> {
+        // start backend service
+        let url = spawn_backend(router).await?;
+        // configuration of Mnemosyne
+        let config = Config::builder().endpoint("example.com", url).build();
+        // state of Mnemosyne
+        let state = new_state(config);
+        // router
+        // start Mnemosyne
+        let app = app_main(state, OpenApi::default());
+        Ok(TestServer::new(app).unwrap())
+    }
+    #[tokio::test]
+    async fn first_request() -> Result<()> {
+        // tracing_subscriber::fmt::init();
+        let app = app().await.unwrap();
+        // send get request for the first time
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn correct_etag() -> Result<()> {
+        // tracing_subscriber::fmt::init();
+        let app = app().await.unwrap();
+        // send get request for the first time
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        let etag = rep.headers().get(ETAG).unwrap();
+        // wait for the cache to save the entry.
+        sleep(Duration::from_millis(100)).await;
+        // resend same request with the etag, quoted or not, alone or in a list
+        let etag = etag.to_str()?;
+        for if_none_match in [
+            format!("\"{etag}\""),
+            etag.to_string(),
+            format!("W/\"{etag}\""),
+            format!("\"{}\", \"{etag}\"", Uuid::new_v4()),
+            "*".to_string(),
+        ] {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(IF_NONE_MATCH, HeaderValue::from_str(&if_none_match)?)
+                .await;
+            // response should only contains header not modified without the body
+            rep.assert_status(StatusCode::NOT_MODIFIED);
+        }
+        // a bare uuid in the ETag header is still accepted
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(ETAG, HeaderValue::from_str(etag)?)
+            .await;
+        rep.assert_status(StatusCode::NOT_MODIFIED);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn weak_backend_etag() -> Result<()> {
+        let backend = router_backend().route(
+            "/weak",
+            get(|| async { ([(ETAG, "W/\"v1,2\"")], "Hello, World!") }),
+        );
+        let app = app_with_backend(backend).await?;
+        app.get("/weak")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        sleep(Duration::from_millis(100)).await;
+        // weak comparison, the weak indicator and the quotes do not matter and a comma can be part of a tag.
+        for (if_none_match, status) in [
+            ("W/\"v1,2\"", StatusCode::NOT_MODIFIED),
+            ("\"v1,2\"", StatusCode::NOT_MODIFIED),
+            ("\"v0\", W/\"v1,2\"", StatusCode::NOT_MODIFIED),
+            ("\"v0\",\"v1,2\" , \"v3\"", StatusCode::NOT_MODIFIED),
+            ("W/\"v1\", \"2\"", StatusCode::OK),
+            ("W/\"v2\"", StatusCode::OK),
+        ] {
+            app.get("/weak")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(IF_NONE_MATCH, HeaderValue::from_static(if_none_match))
+                .await
+                .assert_status(status);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn incorrect_etag() -> Result<()> {
+        // tracing_subscriber::fmt::init();
+        let app = app().await.unwrap();
+        // send get request for the first time
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        // wait for the cache to save the entry.
+        sleep(Duration::from_millis(100)).await;
+        // resend same request with the etag
+        let etag = format!("\"{}\"", Uuid::new_v4());
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_NONE_MATCH, HeaderValue::from_str(&etag).unwrap())
+            .await;
+        // response should only contains header not modified without the body
+        rep.assert_status(StatusCode::OK);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn cross_resource_etag() -> Result<()> {
+        let app = app_with_backend(router_backend().route("/other", get(backend_handler))).await?;
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        let etag = rep.headers().get(ETAG).unwrap().clone();
+        app.get("/other")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        // the etag of / is in the cache but it is not the one of /other
+        app.get("/other")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_NONE_MATCH, etag)
+            .await
+            .assert_status_ok();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn cache_served() -> Result<()> {
+        // tracing_subscriber::fmt::init();
+        let app = app().await.unwrap();
+        // send get request for the first time
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        // wait for the cache to save the entry.
+        sleep(Duration::from_millis(100)).await;
+        // check that cache has the entry.
+        let etag = rep.headers().get(ETAG).unwrap();
+        let uri = format!("/api/1/cache/{}", etag.to_str().unwrap());
+        app.get(&uri).await.assert_status_ok();
+        // resend request. response should be served from cache.
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        // response should only contains header not modified without the body
+        Ok(())
+    }
+    #[tokio::test]
+    async fn cache_must_be_empty() -> Result<()> {
+        // tracing_subscriber::fmt::init();
+        let app = app().await.unwrap();
+        // send get request for the first time
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        // wait for the cache to save the entry.
+        sleep(Duration::from_millis(100)).await;
+        // delete the entry
+        let etag = rep.headers().get(ETAG).unwrap();
+        let uri = format!("/api/1/cache/{}", etag.to_str().unwrap());
+        app.delete(&uri).await.assert_status_ok();
+        app.get(&uri).await.assert_status_not_found();
+        // resend request. response should be served from cache.
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        // response should only contains header not modified without the body
+        Ok(())
+    }
+    #[tokio::test]
+    async fn invalidate_cache_per_path() -> Result<()> {
+        // tracing_subscriber::fmt::init();
+        let app = app().await.unwrap();
+        // send get request for the first time
+        let rep = app
+            .get("/abc")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        // wait for the cache to save the entry.
+        sleep(Duration::from_millis(100)).await;
+        // delete the entry per path
+        let etag = rep.headers().get(ETAG).unwrap();
+        let uri = "/api/1/cache/path/abc";
+        app.delete(uri)
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        let uri_uuid = format!("/api/1/cache/{}", etag.to_str().unwrap());
+        app.get(&uri_uuid)
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_not_found();
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn invalidate_cache_per_signature() -> Result<()> {
+        // tracing_subscriber::fmt::init();
+        let app = app().await.unwrap();
+        // send get request for the first time
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        // wait for the cache to save the entry.
+        sleep(Duration::from_millis(100)).await;
+        // delete the entry per signature
+        let etag = rep.headers().get(ETAG).unwrap();
+        let uri = "/api/1/cache/lookup?method=GET&path=/&host=example.com";
+        app.delete(uri).await.assert_status_ok();
+        let uri_uuid = format!("/api/1/cache/{}", etag.to_str().unwrap());
+        app.get(&uri_uuid).await.assert_status_not_found();
+        // nothing left to delete for this signature.
+        app.delete(uri).await.assert_status_not_found();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn always_revalidate() -> Result<()> {
+        // tracing_subscriber::fmt::init();
+        // backend counting the requests and the full responses sent.
+        let requests = Arc::new(AtomicUsize::new(0));
+        let full_responses = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let (requests, full_responses) = (requests.clone(), full_responses.clone());
+                move |headers: HeaderMap| async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    if headers.get(IF_NONE_MATCH) == Some(&HeaderValue::from_static("\"v1\"")) {
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+                    full_responses.fetch_add(1, Ordering::SeqCst);
+                    (
+                        [
+                            (CACHE_CONTROL, "max-age=0, must-revalidate"),
+                            (ETAG, "\"v1\""),
+                        ],
+                        "Hello, World!",
+                    )
+                        .into_response()
+                }
+            }),
+        );
+        let app = app_with_backend(backend).await?;
+        for _ in 0..3 {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            rep.assert_status_ok();
+            rep.assert_text("Hello, World!");
+            // wait for the cache to save the entry.
+            sleep(Duration::from_millis(100)).await;
+        }
+        // every request was revalidated, but the body was only downloaded once.
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(full_responses.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn response_no_cache() -> Result<()> {
+        // backend counting the conditional requests
+        let conditional = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let conditional = conditional.clone();
+                move |headers: HeaderMap| async move {
+                    if headers.get(IF_NONE_MATCH) == Some(&HeaderValue::from_static("\"v1\"")) {
+                        conditional.fetch_add(1, Ordering::SeqCst);
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+                    (
+                        [
+                            (CACHE_CONTROL, "no-cache, stale-while-revalidate=60"),
+                            (ETAG, "\"v1\""),
+                        ],
+                        "Hello, World!",
+                    )
+                        .into_response()
+                }
+            }),
+        );
+        let app = app_with_backend(backend).await?;
+        for _ in 0..2 {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_text("Hello, World!");
+            sleep(Duration::from_millis(100)).await;
+        }
+        // the entry is stored but revalidated before being served again
+        assert_eq!(conditional.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn revalidate_last_modified() -> Result<()> {
+        // backend without ETag, the stale entry is revalidated with its Last-Modified date.
+        let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let requests = Arc::new(AtomicUsize::new(0));
+        let full_responses = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let (requests, full_responses) = (requests.clone(), full_responses.clone());
+                move |headers: HeaderMap| async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    if headers.get(IF_MODIFIED_SINCE)
+                        == Some(&HeaderValue::from_static(last_modified))
+                    {
+                        // the 304 response extends the freshness of the entry.
+                        return (StatusCode::NOT_MODIFIED, [(CACHE_CONTROL, "max-age=60")])
+                            .into_response();
+                    }
+                    full_responses.fetch_add(1, Ordering::SeqCst);
+                    (
+                        [(CACHE_CONTROL, "max-age=1"), (LAST_MODIFIED, last_modified)],
+                        "Hello, World!",
+                    )
+                        .into_response()
+                }
+            }),
+        );
+        let app = app_with_backend(backend).await?;
+        for wait in [1100, 100, 100] {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            rep.assert_status_ok();
+            rep.assert_text("Hello, World!");
+            sleep(Duration::from_millis(wait)).await;
+        }
+        // the stale entry was revalidated once, then served fresh from the cache.
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(full_responses.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn backend_not_modified() -> Result<()> {
+        // backend answering the conditional headers of the client itself.
+        let backend = Router::new().route(
+            "/",
+            get(|headers: HeaderMap| async move {
+                if headers.contains_key(IF_NONE_MATCH) {
+                    return StatusCode::NOT_MODIFIED.into_response();
+                }
+                "Hello, World!".into_response()
+            }),
+        );
+        let url = spawn_backend(backend).await?;
+        // even if 304 is configured as cacheable, the empty response is not stored.
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                cacheable_statuses: vec![200, 304],
+                ..Default::default()
+            })
+            .build();
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_NONE_MATCH, HeaderValue::from_static("\"backend\""))
+            .await;
+        rep.assert_status(StatusCode::NOT_MODIFIED);
+        assert_eq!(rep.headers().get("x-cache").unwrap(), "MISS");
+        sleep(Duration::from_millis(100)).await;
+        assert!(state.index_cache.is_empty());
+        // the next client gets the full response.
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, World!");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn revalidate_without_client_validators() -> Result<()> {
+        // backend without validators, answering 304 to any conditional request.
+        let requests = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let requests = requests.clone();
+                move |headers: HeaderMap| async move {
+                    if headers.contains_key(IF_NONE_MATCH)
+                        || headers.contains_key(IF_MODIFIED_SINCE)
+                    {
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+                    let n = requests.fetch_add(1, Ordering::SeqCst);
+                    ([(CACHE_CONTROL, "max-age=1")], format!("response {n}")).into_response()
+                }
+            }),
+        );
+        // stale-if-error keeps the stale entry in the cache.
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                stale_if_error_secs: 60,
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_text("response 0");
+        sleep(Duration::from_millis(1100)).await;
+        // the stale entry is fetched again, the validators of the client are not sent to the backend service.
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_NONE_MATCH, HeaderValue::from_static("\"other\""))
+            .add_header(
+                IF_MODIFIED_SINCE,
+                HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+            )
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text("response 1");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn config_builder() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config::builder()
+            .listen_address("127.0.0.1:9831".parse()?)
+            .endpoint("example.com", url)
+            .fallback(Url::parse("http://127.0.0.1:1000")?)
+            .cache(CacheConfig {
+                expiration: 60,
+                size_limit: 10,
+                ..Default::default()
+            })
+            .build();
+        assert_eq!(config.endpoints.len(), 1);
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        Ok(())
+    }
+    // records the fields of every tracing event
+    #[derive(Clone, Default)]
+    struct EventRecorder(Arc<std::sync::Mutex<Vec<HashMap<String, String>>>>);
+    impl<S: Subscriber> Layer<S> for EventRecorder {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut fields = FieldsRecorder::default();
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+    }
+    #[derive(Default)]
+    struct FieldsRecorder(HashMap<String, String>);
+    impl Visit for FieldsRecorder {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+    #[tokio::test]
+    async fn structured_cache_events() -> Result<()> {
+        let recorder = EventRecorder::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(recorder.clone()));
+        let app = app().await.unwrap();
+        // first request is a miss, second one a hit
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        let uuid = rep.headers().get(ETAG).unwrap().to_str()?.to_string();
+        // a conditional request answered with 304 is a hit too
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_NONE_MATCH, HeaderValue::from_str(&uuid)?)
+            .await
+            .assert_status(StatusCode::NOT_MODIFIED);
+        let events = recorder.0.lock().unwrap();
+        let cache_events = events
+            .iter()
+            .filter(|e| e.contains_key("cache.result"))
+            .collect::<Vec<_>>();
+        assert_eq!(cache_events.len(), 3);
+        assert_eq!(cache_events[0]["cache.result"], "miss");
+        assert!(!cache_events[0].contains_key("cache.uuid"));
+        for event in &cache_events[1..] {
+            assert_eq!(event["cache.result"], "hit");
+            assert_eq!(event["cache.uuid"], uuid);
+        }
+        for event in cache_events {
+            assert_eq!(event["http.method"], "GET");
+            assert_eq!(event["http.path"], "/");
+            assert_eq!(event["http.host"], "example.com");
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn access_log() -> Result<()> {
+        let recorder = EventRecorder::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(recorder.clone()));
+        let url = spawn_backend(router_backend()).await?;
+        let state = new_state(Config {
+            access_log: AccessLogFormat::Plain,
+            ..Config::builder().endpoint("example.com", url).build()
+        });
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        let get = || {
+            app.get("/?page=1")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+        };
+        get().await.assert_status_ok();
+        sleep(Duration::from_millis(100)).await;
+        state.config.write().await.access_log = AccessLogFormat::Json;
+        get().await.assert_status_ok();
+        state.config.write().await.access_log = AccessLogFormat::Off;
+        get().await.assert_status_ok();
+        let events = recorder.0.lock().unwrap();
+        let lines = events
+            .iter()
+            .filter_map(|e| e.get("message"))
+            .filter(|m| m.starts_with("GET ") || m.starts_with('{'))
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let plain = lines[0].split(' ').collect::<Vec<_>>();
+        assert_eq!(
+            plain[..6],
+            ["GET", "example.com", "/?page=1", "200", "13", "MISS"]
+        );
+        assert!(plain[6].ends_with("ms"));
+        let json: serde_json::Value = serde_json::from_str(lines[1])?;
+        assert_eq!(json["method"], "GET");
+        assert_eq!(json["host"], "example.com");
+        assert_eq!(json["path"], "/?page=1");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["size"], 13);
+        assert_eq!(json["cache"], "HIT");
+        assert!(json["latency_ms"].is_u64());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn chunked_body_too_large() -> Result<()> {
+        // backend sending bodies without Content-Length
+        let chunked = |chunks: Vec<&'static str>| async move {
+            Body::from_stream(stream::iter(
+                chunks.into_iter().map(Ok::<_, std::io::Error>),
+            ))
+        };
+        let backend = Router::new()
+            .route("/small", get(move || chunked(vec!["Hello", ", World!"])))
+            .route(
+                "/large",
+                get(move || chunked(vec!["Hello, World!", "Hello, World!", "Hello, World!"])),
+            );
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                max_cacheable_body_bytes: 20,
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        // body under the limit is cached
+        let rep = app
+            .get("/small")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_text("Hello, World!");
+        assert!(rep.headers().get(ETAG).is_some());
+        // body over the limit is streamed entirely but not cached
+        let rep = app
+            .get("/large")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, World!".repeat(3));
+        assert!(rep.headers().get(ETAG).is_none());
+        sleep(Duration::from_millis(100)).await;
+        app.delete("/api/1/cache/lookup?method=GET&path=/small&host=example.com")
+            .await
+            .assert_status_ok();
+        app.delete("/api/1/cache/lookup?method=GET&path=/large&host=example.com")
+            .await
+            .assert_status_not_found();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn max_entry_size() -> Result<()> {
+        let backend = Router::new()
+            .route("/small", get(|| async { "Hello, World!" }))
+            .route("/large", get(|| async { "Hello, World!".repeat(3) }));
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(toml::from_str("max_entry_size = 20")?)
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        for (path, body) in [
+            ("/small", "Hello, World!".to_string()),
+            ("/large", "Hello, World!".repeat(3)),
+        ] {
+            let rep = app
+                .get(path)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            rep.assert_status_ok();
+            rep.assert_text(body);
+        }
+        sleep(Duration::from_millis(100)).await;
+        let stats = app.get("/api/1/cache").await.json::<serde_json::Value>();
+        assert_eq!(stats["entries"], 1);
+        app.delete("/api/1/cache/lookup?method=GET&path=/large&host=example.com")
+            .await
+            .assert_status_not_found();
+        // priming is limited too
+        app.put("/api/1/cache")
+            .json(&serde_json::json!({
+                "method": "GET",
+                "path": "/primed",
+                "host": "example.com",
+                "status": 200,
+                "body": BASE64_STANDARD.encode("Hello, World!".repeat(3)),
+            }))
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn maintenance_mode() -> Result<()> {
+        let app = app().await.unwrap();
+        // cache an entry
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        sleep(Duration::from_millis(100)).await;
+        app.post("/api/1/config/maintenance")
+            .text("true")
+            .await
+            .assert_status_ok();
+        // hit is still served
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        // miss does not reach the backend service
+        app.get("/abc")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        app.post("/api/1/config/maintenance")
+            .text("maybe")
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        app.post("/api/1/config/maintenance")
+            .text("false")
+            .await
+            .assert_status_ok();
+        app.get("/abc")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_not_found();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn retry_after() -> Result<()> {
+        let config = Config::builder().build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        app.post("/api/1/config/maintenance")
+            .text("true")
+            .await
+            .assert_status_ok();
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rep.headers().get(RETRY_AFTER).unwrap(), "60");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn last_fetch_date() -> Result<()> {
+        let app = app().await.unwrap();
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        let now = SystemTime::now();
+        // metadata gives a recent fetch date
+        let etag = rep.headers().get(ETAG).unwrap().to_str()?;
+        let metadata = app
+            .get(&format!("/api/1/cache/{etag}/metadata"))
+            .await
+            .json::<serde_json::Value>();
+        let fetched_at = UNIX_EPOCH + Duration::from_secs(metadata["fetched_at"].as_u64().unwrap());
+        assert!(now.duration_since(fetched_at)? < Duration::from_secs(5));
+        // hit contains the date of the fetch
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        let date = httpdate::parse_http_date(rep.headers().get("x-cache-date").unwrap().to_str()?)?;
+        assert!(now.duration_since(date)? < Duration::from_secs(5));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn malformed_and_absent_uuid() -> Result<()> {
+        let app = app().await.unwrap();
+        let absent = Uuid::new_v4();
+        for uri in [
+            "/api/1/cache/not-a-uuid",
+            "/api/1/cache/not-a-uuid/metadata",
+        ] {
+            app.get(uri).await.assert_status_bad_request();
+        }
+        app.delete("/api/1/cache/not-a-uuid")
+            .await
+            .assert_status_bad_request();
+        for uri in [
+            format!("/api/1/cache/{absent}"),
+            format!("/api/1/cache/{absent}/metadata"),
+        ] {
+            app.get(&uri).await.assert_status_not_found();
+        }
+        app.delete(&format!("/api/1/cache/{absent}"))
+            .await
+            .assert_status_not_found();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn cache_size_is_stored_size() -> Result<()> {
+        let app = app().await.unwrap();
+        let mut stored_size = 0;
+        for path in ["/", "/abc"] {
+            let rep = app
+                .get(path)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            sleep(Duration::from_millis(100)).await;
+            let etag = rep.headers().get(ETAG).unwrap().to_str()?;
+            let metadata = app
+                .get(&format!("/api/1/cache/{etag}/metadata"))
+                .await
+                .json::<serde_json::Value>();
+            assert!(metadata["stored_size"].as_u64().unwrap() > metadata["size"].as_u64().unwrap());
+            stored_size += metadata["stored_size"].as_u64().unwrap();
+        }
+        // the size of the cache is the sum of the stored size of its entries.
+        let stats = app.get("/api/1/cache").await.json::<serde_json::Value>();
+        assert_eq!(stats["entries"], 2);
+        assert_eq!(stats["size"].as_u64().unwrap(), stored_size);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn deduplicate_purges() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config {
+            purge_dedup_window: 1000,
+            ..Config::builder().endpoint("example.com", url).build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let cache = |app: &TestServer| {
+            app.get("/abc")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+        };
+        cache(&app).await;
+        sleep(Duration::from_millis(100)).await;
+        app.delete("/api/1/cache/path/abc")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        // cache the entry again, the identical purge fired right after is a no-op.
+        let rep = cache(&app).await;
+        sleep(Duration::from_millis(100)).await;
+        app.delete("/api/1/cache/path/abc")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        let etag = rep.headers().get(ETAG).unwrap().to_str()?;
+        let metadata = format!("/api/1/cache/{etag}/metadata");
+        app.get(&metadata).await.assert_status_ok();
+        // once the window elapsed, the purge is done again.
+        sleep(Duration::from_millis(1000)).await;
+        app.delete("/api/1/cache/path/abc")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        app.get(&metadata).await.assert_status_not_found();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn purge_path_methods() -> Result<()> {
+        let app = app().await?;
+        for method in ["GET", "OPTIONS", "PROPFIND"] {
+            app.put("/api/1/cache")
+                .json(&serde_json::json!({
+                    "method": method,
+                    "path": "/multi",
+                    "host": "example.com",
+                    "status": 200,
+                }))
+                .await
+                .assert_status(StatusCode::CREATED);
+        }
+        let entries = |app: &TestServer| app.get("/api/1/cache/entries");
+        // only the entries of the method given
+        app.delete("/api/1/cache/path/multi")
+            .add_query_param("method", "options")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        assert_eq!(entries(&app).await.json::<serde_json::Value>()["total"], 2);
+        // every method
+        app.delete("/api/1/cache/path/multi")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        assert_eq!(entries(&app).await.json::<serde_json::Value>()["total"], 0);
+        // malformed path or method
+        app.delete("/api/1/cache/path/a%20b")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_bad_request();
+        app.delete("/api/1/cache/path/multi")
+            .add_query_param("method", "not a method")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_bad_request();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn purge_tag() -> Result<()> {
+        let backend = Router::new()
+            .route(
+                "/a",
+                get(|| async { ([("surrogate-key", "news article-1")], "a") }),
+            )
+            .route(
+                "/b",
+                get(|| async { ([("surrogate-key", "article-2 news")], "b") }),
+            )
+            .route("/c", get(|| async { "c" }));
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        for path in ["/a", "/b", "/c"] {
+            app.get(path)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_status_ok();
+        }
+        sleep(Duration::from_millis(100)).await;
+        app.delete("/api/1/cache/tag/news").await.assert_status_ok();
+        let page = app
+            .get("/api/1/cache/entries")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["entries"][0]["path"], "/c");
+        // the other tags of the purged entries are gone too
+        app.delete("/api/1/cache/tag/article-1")
+            .await
+            .assert_status_not_found();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn tags_of_expired_entries() -> Result<()> {
+        let backend = Router::new().route(
+            "/",
+            get(|| async { ([("surrogate-key", "news")], "Hello, World!") }),
+        );
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                expiration: 1,
+                ..Default::default()
+            })
+            .build();
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        sleep(Duration::from_millis(1100)).await;
+        // the expired entry is not purged, even before its tags are removed from the index
+        app.delete("/api/1/cache/tag/news")
+            .await
+            .assert_status_not_found();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        sleep(Duration::from_millis(1100)).await;
+        // the tags are removed with the expired entry
+        state.cache.run_pending_tasks().await;
+        assert!(state.index_cache.delete_tag("news").is_empty());
+        Ok(())
+    }
+    // backend counting the requests received on /
+    fn counting_backend() -> (Router, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let router = Router::new().route(
+            "/",
+            get({
+                let requests = requests.clone();
+                move || async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    "Hello, World!"
+                }
+            }),
+        );
+        (router, requests)
+    }
+    #[tokio::test]
+    async fn credentials_not_cached() -> Result<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().fallback({
+            let requests = requests.clone();
+            move |uri: Uri| async move {
+                requests.fetch_add(1, Ordering::SeqCst);
+                let mut headers = HeaderMap::new();
+                if uri.path() == "/cookie" {
+                    headers.insert(SET_COOKIE, HeaderValue::from_static("session=secret"));
+                }
+                (headers, "Hello, World!")
+            }
+        });
+        let url = spawn_backend(backend).await?;
+        for allowed in [false, true] {
+            requests.store(0, Ordering::SeqCst);
+            let config = Config::builder()
+                .endpoint("example.com", url.clone())
+                .cache(CacheConfig {
+                    cache_set_cookie: allowed,
+                    cache_authorized_requests: allowed,
+                    ..Default::default()
+                })
+                .build();
+            let state = new_state(config);
+            let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+            for _ in 0..2 {
+                let rep = app
+                    .get("/cookie")
+                    .add_header(HOST, HeaderValue::from_static("example.com"))
+                    .await;
+                assert_eq!(rep.headers().get(SET_COOKIE).unwrap(), "session=secret");
+                app.get("/")
+                    .add_header(HOST, HeaderValue::from_static("example.com"))
+                    .add_header(AUTHORIZATION, HeaderValue::from_static("Bearer user"))
+                    .await
+                    .assert_text("Hello, World!");
+                sleep(Duration::from_millis(100)).await;
+            }
+            // both are proxied every time and never stored, unless allowed
+            let expected = if allowed { 2 } else { 4 };
+            assert_eq!(requests.load(Ordering::SeqCst), expected);
+            assert_eq!(state.index_cache.is_empty(), !allowed);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn non_cacheable_method() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                non_cacheable_methods: vec!["HEAD".to_string()],
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        // HEAD requests always reach the backend service
+        for _ in 0..2 {
+            let rep = app
+                .method(Method::HEAD, "/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            rep.assert_status_ok();
+            assert!(rep.headers().get(ETAG).is_none());
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // GET requests are still cached
+        for _ in 0..2 {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_status_ok();
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn idempotent_methods() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let posts = Arc::new(AtomicUsize::new(0));
+        let backend = backend.route(
+            "/form",
+            post({
+                let posts = posts.clone();
+                move || async move {
+                    posts.fetch_add(1, Ordering::SeqCst);
+                    "Posted"
+                }
+            }),
+        );
+        let url = spawn_backend(backend).await?;
+        let state = new_state(Config::builder().endpoint("example.com", url).build());
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        // POST requests always reach the backend service and are not cached
+        for _ in 0..2 {
+            let rep = app
+                .post("/form")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            rep.assert_status_ok();
+            rep.assert_text("Posted");
+            assert!(rep.headers().get(ETAG).is_none());
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(posts.load(Ordering::SeqCst), 2);
+        assert!(state.index_cache.is_empty());
+        assert_eq!(state.cache.entry_count(), 0);
+        // HEAD request reuses the headers of the GET entry
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        let etag = rep.headers().get(ETAG).unwrap().clone();
+        sleep(Duration::from_millis(100)).await;
+        let rep = app
+            .method(Method::HEAD, "/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        assert_eq!(rep.headers().get(ETAG), Some(&etag));
+        assert!(rep.as_bytes().is_empty());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn head_from_get() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let state = new_state(Config::builder().endpoint("example.com", url).build());
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        // without a GET entry, HEAD is passed through and not cached as its own entry
+        app.method(Method::HEAD, "/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        sleep(Duration::from_millis(100)).await;
+        assert!(state.index_cache.is_empty());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        sleep(Duration::from_millis(100)).await;
+        let rep = app
+            .method(Method::HEAD, "/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        assert!(rep.as_bytes().is_empty());
+        // the length is the one of the body a GET request would get
+        assert_eq!(rep.headers().get(CONTENT_LENGTH).unwrap(), "13");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn x_cache_header() -> Result<()> {
+        let (backend, _) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let app = TestServer::new(app_main(
+            new_state(Config::builder().endpoint("example.com", url).build()),
+            OpenApi::default(),
+        ))
+        .unwrap();
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        assert_eq!(rep.headers().get("x-cache").unwrap(), "MISS");
+        assert!(rep.headers().get(AGE).is_none());
+        sleep(Duration::from_millis(100)).await;
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        assert_eq!(rep.headers().get("x-cache").unwrap(), "HIT");
+        assert_eq!(rep.headers().get(AGE).unwrap(), "0");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn age_header() -> Result<()> {
+        let backend = Router::new()
+            .route("/", get(|| async { "fresh" }))
+            .route("/aged", get(|| async { ([(AGE, "10")], "aged") }));
+        let url = spawn_backend(backend).await?;
+        let app = TestServer::new(app_main(
+            new_state(Config::builder().endpoint("example.com", url).build()),
+            OpenApi::default(),
+        ))
+        .unwrap();
+        let get = |path: &'static str| {
+            app.get(path)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+        };
+        get("/").await.assert_status_ok();
+        get("/aged").await.assert_status_ok();
+        app.put("/api/1/cache")
+            .json(&serde_json::json!({
+                "method": "GET",
+                "path": "/primed",
+                "host": "example.com",
+                "status": 200,
+            }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        sleep(Duration::from_millis(1100)).await;
+        let rep = get("/").await;
+        assert_eq!(rep.headers().get(AGE).unwrap(), "1");
+        // the age given by the backend service is added
+        let rep = get("/aged").await;
+        assert_eq!(rep.headers().get(AGE).unwrap(), "11");
+        // an entry without Date is given the date it was stored
+        let rep = get("/primed").await;
+        assert_eq!(rep.headers().get(AGE).unwrap(), "1");
+        assert_eq!(rep.headers().get(DATE), rep.headers().get("x-cache-date"));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn retry_backend_connection() -> Result<()> {
+        // port of a backend service not listening yet
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        drop(listener);
+        let url = Url::parse(&format!("http://{address}"))?;
+        let config = Config {
+            backend_max_attempts: 3,
+            backend_retry_delay_ms: 300,
+            ..Config::builder().endpoint("example.com", url).build()
+        };
+        let app = TestServer::new(app_main(new_state(config.clone()), OpenApi::default())).unwrap();
+        // the backend service starts between the first and the second attempt
+        spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            let listener = TcpListener::bind(address).await.unwrap();
+            app_backend(listener, router_backend()).await.unwrap();
+        });
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, World!");
+        // without retries, the failed connection gives up at once
+        let config = Config {
+            backend_max_attempts: 1,
+            ..config
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}", listener.local_addr()?))?;
+        drop(listener);
+        let config = Config {
+            endpoints: vec![Endpoint::new("example.com", url)],
+            ..config
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn circuit_breaker() -> Result<()> {
+        // port of a backend service down
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        drop(listener);
+        let url = Url::parse(&format!("http://{address}"))?;
+        let config = Config {
+            breaker_threshold: 2,
+            breaker_cooldown_ms: 500,
+            ..Config::builder().endpoint("example.com", url).build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let request = || {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+        };
+        for _ in 0..2 {
+            request()
+                .await
+                .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        // the breaker is open, the backend service is not requested anymore
+        let rep = request().await;
+        rep.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rep.headers().get(RETRY_AFTER).unwrap(), "60");
+        // the backend service is back, the probe after the cooldown closes the breaker
+        let listener = TcpListener::bind(address).await?;
+        spawn(async move { app_backend(listener, router_backend()).await });
+        request()
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        sleep(Duration::from_millis(500)).await;
+        request().await.assert_status_ok();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn ready_follows_breakers() -> Result<()> {
+        // port of a backend service down
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        drop(listener);
+        let url = Url::parse(&format!("http://{address}"))?;
+        let config = Config {
+            breaker_threshold: 1,
+            breaker_cooldown_ms: 60000,
+            strict_routing: true,
+            ..Config::builder().endpoint("example.com", url).build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        app.get("/ready").await.assert_status_ok();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        // the only backend service of the endpoint has an open breaker
+        app.get("/ready")
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn stale_while_revalidate() -> Result<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let requests = requests.clone();
+                move || async move {
+                    let n = requests.fetch_add(1, Ordering::SeqCst) + 1;
+                    // the revalidations are slow
+                    if n > 1 {
+                        sleep(Duration::from_millis(300)).await;
+                    }
+                    (
+                        [(CACHE_CONTROL, "max-age=1, stale-while-revalidate=30")],
+                        format!("version {n}"),
+                    )
+                }
+            }),
+        );
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let request = || {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+        };
+        request().await.assert_text("version 1");
+        sleep(Duration::from_millis(1100)).await;
+        // stale, served at once while revalidated in the background
+        let start = Instant::now();
+        request().await.assert_text("version 1");
+        assert!(start.elapsed() < Duration::from_millis(300));
+        sleep(Duration::from_millis(500)).await;
+        request().await.assert_text("version 2");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn purge_during_revalidation() -> Result<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let requests = requests.clone();
+                move || async move {
+                    let n = requests.fetch_add(1, Ordering::SeqCst) + 1;
+                    (
+                        [(CACHE_CONTROL, "max-age=1, stale-while-revalidate=30")],
+                        format!("version {n}"),
+                    )
+                }
+            }),
+        );
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        let request = || {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+        };
+        request().await.assert_text("version 1");
+        sleep(Duration::from_millis(1100)).await;
+        // stale, the revalidation is spawned but has not looked up the entry yet
+        request().await.assert_text("version 1");
+        // the entry is purged before the revalidation looks it up
+        state.index_cache.clear();
+        state.cache.invalidate_all();
+        // the revalidation does not wait for itself, the request is not blocked
+        tokio::time::timeout(Duration::from_secs(2), request())
+            .await?
+            .assert_status_ok();
+        tokio::time::timeout(Duration::from_secs(2), shutdown(&state)).await?;
+        Ok(())
+    }
+    #[tokio::test]
+    async fn stale_if_error() -> Result<()> {
+        // backend failing after its first response
+        let backend = || {
+            let requests = Arc::new(AtomicUsize::new(0));
+            Router::new().route(
+                "/",
+                get(move || async move {
+                    if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                        ([(CACHE_CONTROL, "max-age=1")], "Hello, World!").into_response()
+                    } else {
+                        StatusCode::BAD_GATEWAY.into_response()
+                    }
+                }),
+            )
+        };
+        for stale_if_error_secs in [60, 0] {
+            let url = spawn_backend(backend()).await?;
+            let config = Config::builder()
+                .endpoint("example.com", url)
+                .cache(CacheConfig {
+                    stale_if_error_secs,
+                    ..Default::default()
+                })
+                .build();
+            let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+            let request = || {
+                app.get("/")
+                    .add_header(HOST, HeaderValue::from_static("example.com"))
+            };
+            request().await.assert_status_ok();
+            sleep(Duration::from_millis(1100)).await;
+            let rep = request().await;
+            if stale_if_error_secs > 0 {
+                rep.assert_status_ok();
+                rep.assert_text("Hello, World!");
+                assert_eq!(
+                    rep.headers().get(WARNING).unwrap(),
+                    "111 - \"Revalidation Failed\""
+                );
+            } else {
+                rep.assert_status(StatusCode::BAD_GATEWAY);
+            }
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn must_revalidate() -> Result<()> {
+        // backend failing after its first response, which forbids serving it stale
+        for directive in ["must-revalidate", "proxy-revalidate"] {
+            let requests = Arc::new(AtomicUsize::new(0));
+            let backend = Router::new().route(
+                "/",
+                get(move || async move {
+                    if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                        let cache_control =
+                            format!("max-age=1, stale-while-revalidate=60, {directive}");
+                        ([(CACHE_CONTROL, cache_control)], "Hello, World!").into_response()
+                    } else {
+                        StatusCode::BAD_GATEWAY.into_response()
+                    }
+                }),
+            );
+            let url = spawn_backend(backend).await?;
+            let config = Config::builder()
+                .endpoint("example.com", url)
+                .cache(CacheConfig {
+                    stale_if_error_secs: 60,
+                    ..Default::default()
+                })
+                .build();
+            let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+            let request = || {
+                app.get("/")
+                    .add_header(HOST, HeaderValue::from_static("example.com"))
+            };
+            request().await.assert_status_ok();
+            sleep(Duration::from_millis(1100)).await;
+            // neither stale-while-revalidate nor stale-if-error serve the stale entry
+            request().await.assert_status(StatusCode::BAD_GATEWAY);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn fallback_loop() -> Result<()> {
+        let config = Config::builder()
+            .listen_address("127.0.0.1:9830".parse()?)
+            .fallback(Url::parse("http://127.0.0.1:9830")?)
+            .build();
+        assert!(config.check_loop().is_err());
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("unknown.com"))
+            .await
+            .assert_status(StatusCode::LOOP_DETECTED);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn preview_route() -> Result<()> {
+        let config = Config::builder()
+            .endpoint("example.com", Url::parse("http://127.0.0.1:9934/v1/")?)
+            .fallback(Url::parse("http://127.0.0.1:1000")?)
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        // matched host
+        app.get("/api/1/config/route?host=example.com&path=/abc")
+            .await
+            .assert_text("http://127.0.0.1:9934/v1/abc");
+        // unknown host goes to the fallback
+        app.get("/api/1/config/route?host=example.net&path=/abc")
+            .await
+            .assert_text("http://127.0.0.1:1000/abc");
+        // no host goes to the fallback
+        app.get("/api/1/config/route")
+            .await
+            .assert_text("http://127.0.0.1:1000/");
+        app.get("/api/1/config/route?path=abc")
+            .await
+            .assert_status_bad_request();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn default_ttl() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                default_ttl_secs: 1,
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        for _ in 0..2 {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_status_ok();
+            sleep(Duration::from_millis(100)).await;
+        }
+        // second request was served from cache
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // the response without freshness headers expires after the default ttl
+        sleep(Duration::from_millis(1000)).await;
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn heuristic_freshness() -> Result<()> {
+        let last_modified = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(100));
+        let backend = Router::new().route(
+            "/",
+            get(move || async move { ([(LAST_MODIFIED, last_modified)], "Hello, World!") }),
+        );
+        let url = spawn_backend(backend).await?;
+        for (max_ttl, expected) in [(86400, 10), (5, 5)] {
+            let config = Config::builder()
+                .endpoint("example.com", url.clone())
+                .cache(CacheConfig {
+                    heuristic_freshness: true,
+                    heuristic_max_ttl_secs: max_ttl,
+                    ..Default::default()
+                })
+                .build();
+            let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            sleep(Duration::from_millis(100)).await;
+            let etag = rep.headers().get(ETAG).unwrap().to_str()?;
+            let metadata = app
+                .get(&format!("/api/1/cache/{etag}/metadata"))
+                .await
+                .json::<serde_json::Value>();
+            // 10% of the 100 seconds since last modification, clamped to the maximum.
+            assert_eq!(metadata["freshness"], expected);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn invalidate_on_config_change() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config {
+            invalidate_on_config_change: true,
+            ..Config::builder()
+                .endpoint("example.com", url.clone())
+                .build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        let etag = rep.headers().get(ETAG).unwrap().to_str()?;
+        app.get(&format!("/api/1/cache/{etag}/metadata"))
+            .await
+            .assert_status_ok();
+        // changing the backend of the endpoint purges its entries
+        app.put("/api/1/config/endpoint/example.com")
+            .text(url.to_string())
+            .await
+            .assert_status_ok();
+        app.get(&format!("/api/1/cache/{etag}/metadata"))
+            .await
+            .assert_status_not_found();
+        // same for deleting the endpoint
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        let etag = rep.headers().get(ETAG).unwrap().to_str()?;
+        app.delete("/api/1/config/endpoint/example.com")
+            .await
+            .assert_status_ok();
+        app.get(&format!("/api/1/cache/{etag}/metadata"))
+            .await
+            .assert_status_not_found();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn invalidate_matched_hosts() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config {
+            invalidate_on_config_change: true,
+            ..Config::builder()
+                .endpoint("*.example.com", url.clone())
+                .endpoint("example.org", url.clone())
+                .build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let cached = |host: &'static str| {
+            let app = &app;
+            async move {
+                let rep = app
+                    .get("/")
+                    .add_header(HOST, HeaderValue::from_static(host))
+                    .await;
+                sleep(Duration::from_millis(100)).await;
+                let etag = rep.headers().get(ETAG).unwrap().to_str().unwrap();
+                format!("/api/1/cache/{etag}/metadata")
+            }
+        };
+        // the entries of every HOST matching a pattern are purged with its endpoint
+        let metadata = cached("www.example.com").await;
+        app.get(&metadata).await.assert_status_ok();
+        app.put("/api/1/config/endpoint/*.example.com")
+            .text(url.to_string())
+            .await
+            .assert_status_ok();
+        app.get(&metadata).await.assert_status_not_found();
+        // the HOST of the entries is matched ignoring case
+        let metadata = cached("Example.org").await;
+        app.get(&metadata).await.assert_status_ok();
+        app.delete("/api/1/config/endpoint/example.org")
+            .await
+            .assert_status_ok();
+        app.get(&metadata).await.assert_status_not_found();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn replace_endpoint_backend() -> Result<()> {
+        let mut config = Config::builder()
+            .endpoint("example.com", Url::parse("http://127.0.0.1:9934")?)
+            .build();
+        let endpoint = &mut config.endpoints[0];
+        endpoint.backends.push(Backend {
+            url: Url::parse("http://127.0.0.1:9936")?,
+            weight: 3,
+        });
+        endpoint.balance = Balance::RoundRobin;
+        endpoint.client_cache_control = Some("max-age=30".to_string());
+        endpoint.preserve_host = false;
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        app.put("/api/1/config/endpoint/example.com")
+            .text("http://127.0.0.1:9935")
+            .await
+            .assert_status_ok();
+        // only the backend services are replaced, the other settings are kept
+        let config = state.config.read().await;
+        let endpoint = &config.endpoints[0];
+        assert_eq!(config.endpoints.len(), 1);
+        assert_eq!(endpoint.backends.len(), 1);
+        assert_eq!(endpoint.backends[0].url.as_str(), "http://127.0.0.1:9935/");
+        assert_eq!(endpoint.backends[0].weight, 1);
+        assert_eq!(endpoint.balance, Balance::RoundRobin);
+        assert_eq!(endpoint.client_cache_control.as_deref(), Some("max-age=30"));
+        assert!(!endpoint.preserve_host);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn list_endpoints() -> Result<()> {
+        let config = Config::builder()
+            .endpoint("example.com", Url::parse("http://127.0.0.1:9934")?)
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        app.put("/api/1/config/endpoint/other.com")
+            .text("http://127.0.0.1:9935")
+            .await
+            .assert_status_ok();
+        let rep = app.get("/api/1/config/endpoint").await;
+        rep.assert_status_ok();
+        let endpoints = rep.json::<serde_json::Value>();
+        let endpoints = endpoints.as_array().unwrap();
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(endpoints[0]["host"], "example.com");
+        assert_eq!(endpoints[0]["backends"][0]["url"], "http://127.0.0.1:9934/");
+        assert_eq!(endpoints[1]["host"], "other.com");
+        assert_eq!(endpoints[1]["backends"][0]["url"], "http://127.0.0.1:9935/");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn list_entries() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url.clone())
+            .endpoint("other.com", url)
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        for (host, path) in [
+            ("example.com", "/"),
+            ("example.com", "/a"),
+            ("example.com", "/b"),
+            ("other.com", "/"),
+        ] {
+            app.get(path)
+                .add_header(HOST, HeaderValue::from_static(host))
+                .await;
+        }
+        sleep(Duration::from_millis(100)).await;
+        let page = app
+            .get("/api/1/cache/entries")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(page["total"], 4);
+        assert_eq!(page["entries"][0]["host"], "example.com");
+        assert_eq!(page["entries"][0]["path"], "/");
+        assert_eq!(page["entries"][0]["method"], "GET");
+        assert!(page["entries"][0]["size"].as_u64().unwrap() > 0);
+        // pagination
+        let page = app
+            .get("/api/1/cache/entries?limit=2&offset=1")
+            .await
+            .json::<serde_json::Value>();
+        let entries = page["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["path"], "/a");
+        assert_eq!(entries[1]["path"], "/b");
+        // filter
+        let page = app
+            .get("/api/1/cache/entries?host=other.com")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["entries"][0]["host"], "other.com");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn normalized_query() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let config = Config {
+            normalize_query: true,
+            ..Config::builder().endpoint("example.com", url).build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let rep = app
+            .get("/?a=1&b=2")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        let etag = rep.headers().get(ETAG).unwrap().clone();
+        sleep(Duration::from_millis(100)).await;
+        let rep = app
+            .get("/?b=2&a=1")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        assert_eq!(rep.headers().get(ETAG).unwrap(), etag);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // a purge with the parameters in any order finds the entry
+        app.delete("/api/1/cache/lookup")
+            .add_query_params([
+                ("method", "GET"),
+                ("path", "/?b=2&a=1"),
+                ("host", "example.com"),
+            ])
+            .await
+            .assert_status_ok();
+        // same for a purge per path
+        app.get("/?a=1&b=2")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        sleep(Duration::from_millis(100)).await;
+        app.delete("/api/1/cache/path/%3Fb=2%26a=1")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        // disabled, the order of the parameters makes a different entry
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        for uri in ["/?a=1&b=2", "/?b=2&a=1"] {
+            app.get(uri)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_status_ok();
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn forwarding_headers() -> Result<()> {
+        // backend echoing the forwarding headers it receives
+        let backend = Router::new().fallback(|headers: HeaderMap| async move {
+            ["x-forwarded-for", "x-forwarded-host", "x-forwarded-proto"]
+                .map(|name| headers.get(name).map_or("", |v| v.to_str().unwrap()))
+                .join("|")
+        });
+        let url = spawn_backend(backend).await?;
+        let app = app_main(
+            new_state(Config::builder().endpoint("example.com", url).build()),
+            OpenApi::default(),
+        );
+        let app = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>())?;
+        app.get("/first")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_text("127.0.0.1|example.com|http");
+        // the client address is appended to the chain, a forged host or proto is replaced
+        app.get("/second")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(
+                HeaderName::from_static("x-forwarded-for"),
+                HeaderValue::from_static("203.0.113.7"),
+            )
+            .add_header(
+                HeaderName::from_static("x-forwarded-proto"),
+                HeaderValue::from_static("https"),
+            )
+            .add_header(
+                HeaderName::from_static("x-forwarded-host"),
+                HeaderValue::from_static("evil.example"),
+            )
+            .await
+            .assert_text("203.0.113.7, 127.0.0.1|example.com|http");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn hop_by_hop_headers() -> Result<()> {
+        // backend echoing the names of the custom headers it receives, with a header for its connection only
+        let backend = Router::new().fallback(|headers: HeaderMap| async move {
+            let received = ["x-custom", "x-kept", "keep-alive", "proxy-authorization"]
+                .into_iter()
+                .filter(|name| headers.contains_key(*name))
+                .collect::<Vec<_>>()
+                .join(",");
+            (
+                [
+                    (CONNECTION, "x-internal"),
+                    (HeaderName::from_static("x-internal"), "1"),
+                ],
+                received,
+            )
+        });
+        let url = spawn_backend(backend).await?;
+        let app = TestServer::new(app_main(
+            new_state(Config::builder().endpoint("example.com", url).build()),
+            OpenApi::default(),
+        ))
+        .unwrap();
+        let header = |name: &'static str, value: &'static str| {
+            (
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            )
+        };
+        let mut request = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"));
+        for (name, value) in [
+            header("connection", "X-Custom"),
+            header("x-custom", "1"),
+            header("x-kept", "1"),
+            header("keep-alive", "timeout=5"),
+            header("proxy-authorization", "Basic dXNlcg=="),
+        ] {
+            request = request.add_header(name, value);
+        }
+        let rep = request.await;
+        rep.assert_text("x-kept");
+        assert!(rep.headers().get("x-internal").is_none());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn backend_host_header() -> Result<()> {
+        // backend echoing the HOST it receives
+        let backend = Router::new().fallback(|headers: HeaderMap| async move {
+            headers.get(HOST).unwrap().to_str().unwrap().to_string()
+        });
+        let url = spawn_backend(backend).await?;
+        let mut rewritten = Endpoint::new("rewritten.com", url.clone());
+        rewritten.preserve_host = false;
+        let mut overridden = Endpoint::new("overridden.com", url.clone());
+        overridden.preserve_host = false;
+        overridden.host_header = Some("internal.example".to_string());
+        let config = Config::builder()
+            .endpoint("example.com", url.clone())
+            .with_endpoint(rewritten)
+            .with_endpoint(overridden)
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let backend_address = format!("127.0.0.1:{}", url.port().unwrap());
+        for (host, expected) in [
+            ("example.com", "example.com"),
+            ("rewritten.com", backend_address.as_str()),
+            ("overridden.com", "internal.example"),
+        ] {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_str(host)?)
+                .await
+                .assert_text(expected);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn round_robin_backends() -> Result<()> {
+        let named = |name: &'static str| {
+            Router::new().fallback(move || async move { ([(CACHE_CONTROL, "no-store")], name) })
+        };
+        let first = spawn_backend(named("first")).await?;
+        let second = spawn_backend(named("second")).await?;
+        // port of a backend service not listening
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let dead = Url::parse(&format!("http://{}", listener.local_addr()?))?;
+        drop(listener);
+        let endpoint = |host: &str, urls: [&Url; 2]| {
+            let mut endpoint = Endpoint::new(host, urls[0].clone());
+            endpoint.backends.push(Backend {
+                url: urls[1].clone(),
+                weight: 1,
+            });
+            endpoint.balance = Balance::RoundRobin;
+            endpoint
+        };
+        let config = Config {
+            breaker_threshold: 1,
+            ..Config::builder()
+                .with_endpoint(endpoint("example.com", [&first, &second]))
+                .with_endpoint(endpoint("example.org", [&first, &dead]))
+                .build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let get = |host: &'static str| {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static(host))
+        };
+        for expected in ["first", "second", "first", "second"] {
+            get("example.com").await.assert_text(expected);
+        }
+        // the failing backend service is skipped once its circuit breaker is open
+        get("example.org").await.assert_text("first");
+        get("example.org")
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        for _ in 0..3 {
+            get("example.org").await.assert_text("first");
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn websocket_passthrough() -> Result<()> {
+        // WebSocket backend echoing the messages it receives
+        let backend = TcpListener::bind("127.0.0.1:0").await?;
+        let backend_url = Url::parse(&format!("http://{}", backend.local_addr()?))?;
+        spawn(async move {
+            while let Ok((stream, _)) = backend.accept().await {
+                spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    while let Some(Ok(message)) = ws.next().await {
+                        if message.is_text() && ws.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let state = new_state(Config::builder().fallback(backend_url).build());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let app = app_main(state.clone(), OpenApi::default());
+        spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+        let (mut ws, rep) = tokio_tungstenite::connect_async(format!("ws://{address}/ws")).await?;
+        assert!(rep.headers().get(ETAG).is_none());
+        for text in ["hello", "world"] {
+            ws.send(Message::text(text)).await?;
+            assert_eq!(ws.next().await.unwrap()?, Message::text(text));
+        }
+        ws.close(None).await?;
+        sleep(Duration::from_millis(100)).await;
+        // nothing is cached for an upgraded connection
+        assert!(state.index_cache.is_empty());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn shutdown_with_open_websocket() -> Result<()> {
+        // WebSocket backend keeping the connection open
+        let backend = TcpListener::bind("127.0.0.1:0").await?;
+        let backend_url = Url::parse(&format!("http://{}", backend.local_addr()?))?;
+        spawn(async move {
+            while let Ok((stream, _)) = backend.accept().await {
+                spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    while let Some(Ok(_)) = ws.next().await {}
+                });
+            }
+        });
+        let state = new_state(Config::builder().fallback(backend_url).build());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let app = app_main(state.clone(), OpenApi::default());
+        spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{address}/ws")).await?;
+        ws.send(Message::text("hello")).await?;
+        sleep(Duration::from_millis(100)).await;
+        // the relay is closed instead of waited for
+        tokio::time::timeout(Duration::from_secs(2), shutdown(&state)).await?;
+        assert!(ws.next().await.is_none_or(|message| message.is_err()));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn ttl_override() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                ttl_override_allowlist: vec!["127.0.0.1".parse()?],
+                ..Default::default()
+            })
+            .build();
+        let app = app_main(new_state(config), OpenApi::default());
+        // the client address is only known with a real connection.
+        let app = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>())?;
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(
+                HeaderName::from_static("x-cache-ttl"),
+                HeaderValue::from_static("1"),
+            )
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        let etag = rep.headers().get(ETAG).unwrap().to_str()?;
+        let metadata = app
+            .get(&format!("/api/1/cache/{etag}/metadata"))
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(metadata["freshness"], 1);
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // the entry expires per the header value
+        sleep(Duration::from_millis(1000)).await;
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn shutdown_flushes_inserts() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        // no wait for the spawned insertion
+        shutdown(&state).await;
+        state.cache.run_pending_tasks().await;
+        assert_eq!(state.cache.entry_count(), 1);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn strict_routing() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        for (status, expected) in [
+            (
+                UnknownHostStatus::MisdirectedRequest,
+                StatusCode::MISDIRECTED_REQUEST,
+            ),
+            (UnknownHostStatus::NotFound, StatusCode::NOT_FOUND),
+        ] {
+            let config = Config {
+                strict_routing: true,
+                unknown_host_status: status,
+                ..Config::builder()
+                    .endpoint("example.com", url.clone())
+                    .fallback(url.clone())
+                    .build()
+            };
+            let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("unknown.com"))
+                .await
+                .assert_status(expected);
+            app.get("/").await.assert_status(expected);
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_status_ok();
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn fallback_per_listener() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config::builder().fallback(url).build();
+        let app = app_main(new_state(config), OpenApi::default());
+        let mut addresses = vec![];
+        for fallback in [true, false] {
+            let tcp = TcpListener::bind("127.0.0.1:0").await?;
+            let address = tcp.local_addr()?;
+            let app = app.clone().layer(Extension(Listener { address, fallback }));
+            spawn(async move { axum::serve(tcp, app).await });
+            addresses.push(address);
+        }
+        let client = reqwest::Client::new();
+        let send = |address: SocketAddr| {
+            client
+                .get(format!("http://{address}/"))
+                .header(HOST, "unknown.com")
+                .send()
+        };
+        // internal listener uses the fallback
+        assert_eq!(send(addresses[0]).await?.status(), StatusCode::OK);
+        // public listener refuses unknown HOST
+        assert_eq!(
+            send(addresses[1]).await?.status(),
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn multiple_addresses() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let mut tcps = vec![];
+        for _ in 0..2 {
+            tcps.push(TcpListener::bind("127.0.0.1:0").await?);
+        }
+        let addresses = tcps
+            .iter()
+            .map(|tcp| tcp.local_addr())
+            .collect::<std::io::Result<Vec<_>>>()?;
+        // the additional listener is given as a plain address
+        let mut config: Config = toml::from_str(&format!(
+            "listen_address = \"{}\"\nlisteners = [\"{}\"]",
+            addresses[0], addresses[1]
+        ))?;
+        config.endpoints = Config::builder()
+            .endpoint("example.com", url)
+            .build()
+            .endpoints;
+        let listeners = config.all_listeners();
+        let app = app_main(new_state(config), OpenApi::default());
+        for (tcp, listener) in tcps.into_iter().zip(listeners) {
+            assert_eq!(tcp.local_addr()?, listener.address);
+            let app = app.clone().layer(Extension(listener));
+            spawn(async move { axum::serve(tcp, app).await });
+        }
+        let client = reqwest::Client::new();
+        for (address, cache) in addresses.iter().zip(["MISS", "HIT"]) {
+            let rep = client
+                .get(format!("http://{address}/"))
+                .header(HOST, "example.com")
+                .send()
+                .await?;
+            assert_eq!(rep.status(), StatusCode::OK);
+            // the listeners share the same cache
+            assert_eq!(rep.headers().get("x-cache").unwrap(), cache);
+            sleep(Duration::from_millis(100)).await;
+        }
+        Ok(())
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let app = app_main(new_state(config), OpenApi::default());
+        let path = std::env::temp_dir().join(format!("mnemosyne-{}.sock", Uuid::new_v4()));
+        let stop = tokio_util::sync::CancellationToken::new();
+        // a regular file at the path is refused and kept
+        std::fs::write(&path, "data")?;
+        let refused = serve_uds(&path, app.clone(), stop.clone()).await;
+        assert!(refused.unwrap_err().to_string().contains("not a socket"));
+        assert_eq!(std::fs::read_to_string(&path)?, "data");
+        std::fs::remove_file(&path)?;
+        // a socket file left by a previous run does not prevent the start
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+        let server = spawn({
+            let (path, app, stop) = (path.clone(), app.clone(), stop.clone());
+            async move { serve_uds(&path, app, stop).await }
+        });
+        sleep(Duration::from_millis(100)).await;
+        // the socket of a running instance is not removed
+        let second = serve_uds(&path, app, stop.clone()).await;
+        assert!(second.unwrap_err().to_string().contains("another process"));
+        let mut stream = tokio::net::UnixStream::connect(&path).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut rep = String::new();
+        stream.read_to_string(&mut rep).await?;
+        assert!(rep.starts_with("HTTP/1.1 200 OK"));
+        assert!(rep.ends_with("Hello, World!"));
+        // the socket file is removed on shutdown
+        stop.cancel();
+        server.await??;
+        assert!(!path.exists());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn max_entry_age() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                max_entry_age_secs: 1,
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        for _ in 0..2 {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_status_ok();
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // the entry has no freshness but is older than the ceiling
+        sleep(Duration::from_millis(1000)).await;
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[cfg(feature = "grpc-web")]
+    #[tokio::test]
+    async fn grpc_web_passthrough() -> Result<()> {
+        use axum::body::Bytes;
+        use reqwest::header::CONTENT_TYPE;
+        // a data frame followed by a trailer frame, as a gRPC-web backend would send.
+        const FRAMES: &[u8] = b"\x00\x00\x00\x00\x02\x0a\x00\x80\x00\x00\x00\x0fgrpc-status:0\r\n";
+        let requests = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/helloworld.Greeter/SayHello",
+            post({
+                let requests = requests.clone();
+                move || async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    ([(CONTENT_TYPE, "application/grpc-web+proto")], FRAMES)
+                }
+            }),
+        );
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        for _ in 0..2 {
+            let rep = app
+                .post("/helloworld.Greeter/SayHello")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .content_type("application/grpc-web+proto")
+                .bytes(Bytes::from_static(b"\x00\x00\x00\x00\x00"))
+                .await;
+            rep.assert_status_ok();
+            assert_eq!(
+                rep.headers().get(CONTENT_TYPE).unwrap(),
+                "application/grpc-web+proto"
+            );
+            assert!(rep.headers().get(ETAG).is_none());
+            assert_eq!(rep.as_bytes().as_ref(), FRAMES);
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn prime_entry() -> Result<()> {
+        // the backend service does not serve /primed
+        let app = app().await?;
+        let rep = app
+            .put("/api/1/cache")
+            .json(&serde_json::json!({
+                "method": "GET",
+                "path": "/primed",
+                "host": "example.com",
+                "status": 200,
+                "headers": [
+                    ["content-type", "text/plain"],
+                    ["link", "</a.css>; rel=preload"],
+                    ["link", "</b.js>; rel=preload"],
+                ],
+                "body": "SGVsbG8sIFByaW1lZCE=",
+            }))
+            .await;
+        rep.assert_status(StatusCode::CREATED);
+        let uuid = rep.text();
+        let rep = app
+            .get("/primed")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, Primed!");
+        assert_eq!(rep.headers().get(ETAG).unwrap(), uuid.as_str());
+        // repeated headers are all kept
+        assert_eq!(rep.headers().get_all("link").iter().count(), 2);
+        // the entry only answers the requests of its variation
+        app.put("/api/1/cache")
+            .json(&serde_json::json!({
+                "method": "GET",
+                "path": "/",
+                "host": "example.com",
+                "status": 200,
+                "headers": [["vary", "Accept-Language"]],
+                "request_headers": [["accept-language", "fr"]],
+                "body": "Qm9uam91ciE=",
+            }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        for (language, text) in [("fr", "Bonjour!"), ("en", "Hello, World!")] {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(ACCEPT_LANGUAGE, HeaderValue::from_static(language))
+                .await
+                .assert_text(text);
+        }
+        app.put("/api/1/cache")
+            .json(&serde_json::json!({
+                "method": "GET",
+                "path": "/primed",
+                "host": "example.com",
+                "status": 200,
+                "body": "not base64!",
+            }))
+            .await
+            .assert_status_bad_request();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn prime_entry_endpoint_settings() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let mut endpoint = Endpoint::new("example.com", url);
+        endpoint.cache.default_ttl_secs = Some(42);
+        let config = Config::builder().with_endpoint(endpoint).build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let uuid = app
+            .put("/api/1/cache")
+            .json(&serde_json::json!({
+                "method": "GET",
+                "path": "/primed",
+                "host": "example.com",
+                "status": 200,
+                "body": "SGVsbG8sIFByaW1lZCE=",
+            }))
+            .await
+            .text();
+        // the freshness lifetime of the endpoint is used
+        let metadata = app
+            .get(&format!("/api/1/cache/{}/metadata", uuid.trim_matches('"')))
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(metadata["freshness"], 42);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn missing_host() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        // default HOST
+        let config = Config {
+            default_host: Some("example.com".to_string()),
+            ..Config::builder()
+                .endpoint("example.com", url.clone())
+                .build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let rep = app.get("/").await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, World!");
+        sleep(Duration::from_millis(100)).await;
+        let etag = rep.headers().get(ETAG).unwrap().to_str()?;
+        app.get(&format!("/api/1/cache/{etag}/metadata"))
+            .await
+            .assert_status_ok();
+        // rejected
+        let config = Config {
+            reject_missing_host: true,
+            ..Config::builder().endpoint("example.com", url).build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        app.get("/").await.assert_status_bad_request();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn always_vary_on() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                always_vary_on: vec!["Accept-Language".to_string()],
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let mut etags = HashMap::new();
+        for language in ["en", "fr", "en", "fr"] {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(ACCEPT_LANGUAGE, HeaderValue::from_static(language))
+                .await;
+            sleep(Duration::from_millis(100)).await;
+            let etag = rep.headers().get(ETAG).unwrap().clone();
+            assert_eq!(etags.entry(language).or_insert(etag.clone()), &etag);
+        }
+        // one entry per language, without Vary from the backend service
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_ne!(etags["en"], etags["fr"]);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn max_variants_per_key() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                always_vary_on: vec!["user-agent".to_string()],
+                max_variants_per_key: 3,
+                ..Default::default()
+            })
+            .build();
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        let mut etags = vec![];
+        for i in 0..10 {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(USER_AGENT, HeaderValue::from_str(&format!("agent {i}"))?)
+                .await;
+            etags.push(rep.headers().get(ETAG).unwrap().to_str()?.to_string());
+            sleep(Duration::from_millis(100)).await;
+        }
+        let variants = state
+            .index_cache
+            .iter()
+            .map(|e| e.value().len())
+            .sum::<usize>();
+        assert_eq!(variants, 3);
+        // the oldest variants are evicted from the cache
+        app.get(&format!("/api/1/cache/{}/metadata", etags[0]))
+            .await
+            .assert_status_not_found();
+        app.get(&format!("/api/1/cache/{}/metadata", etags[9]))
+            .await
+            .assert_status_ok();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn orphan_lookups_metric() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        let metrics = app.get("/metrics").await.text();
+        assert!(metrics.contains("mnemosyne_index_keys 1\n"));
+        assert!(metrics.contains("mnemosyne_index_orphan_lookups_total 0\n"));
+        // evicted from the cache but still indexed
+        let uuid = Uuid::parse_str(rep.headers().get(ETAG).unwrap().to_str()?)?;
+        state.cache.invalidate(&uuid).await;
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        let metrics = app.get("/metrics").await.text();
+        assert!(metrics.contains("mnemosyne_index_orphan_lookups_total 1\n"));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn rewrite_body() -> Result<()> {
+        let backend = Router::new().route(
+            "/",
+            get(|| async {
+                axum::Json(serde_json::json!({ "next": "http://10.0.0.2:8080/page/2" }))
+            }),
+        );
+        let url = spawn_backend(backend).await?;
+        let mut endpoint = Endpoint::new("example.com", url);
+        endpoint.rewrite.rules.push(RewriteRule {
+            find: "http://10.0.0.2:8080".to_string(),
+            replace: "https://example.com".to_string(),
+        });
+        let config = Config::builder().with_endpoint(endpoint).build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        for _ in 0..2 {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            rep.assert_json(&serde_json::json!({ "next": "https://example.com/page/2" }));
+            sleep(Duration::from_millis(100)).await;
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn rewrite_skips_streamed_bodies() -> Result<()> {
+        let body = "see http://10.0.0.2:8080/page/2";
+        let backend = Router::new()
+            .route("/", post(move || async move { body }))
+            .route("/large", get(move || async move { body.repeat(10) }));
+        let url = spawn_backend(backend).await?;
+        let mut endpoint = Endpoint::new("example.com", url);
+        endpoint.rewrite.rules.push(RewriteRule {
+            find: "http://10.0.0.2:8080".to_string(),
+            replace: "https://example.com".to_string(),
+        });
+        let config = Config::builder()
+            .with_endpoint(endpoint)
+            .cache(CacheConfig {
+                max_cacheable_body_bytes: 100,
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        // passed through the cache
+        let rep = app
+            .post("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_text(body);
+        // too large to be cached
+        let rep = app
+            .get("/large")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_text(body.repeat(10));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn accept_ranges() -> Result<()> {
+        let app = app().await?;
+        // the response of the backend service is not advertised
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        assert!(rep.headers().get(ACCEPT_RANGES).is_none());
+        sleep(Duration::from_millis(100)).await;
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        assert_eq!(rep.headers().get(ACCEPT_RANGES).unwrap(), "bytes");
+        // the range is served from the cached full body
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(RANGE, HeaderValue::from_static("bytes=0-4"))
+            .await;
+        rep.assert_status(StatusCode::PARTIAL_CONTENT);
+        rep.assert_text("Hello");
+        assert_eq!(rep.headers().get(CONTENT_RANGE).unwrap(), "bytes 0-4/13");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn range_requests() -> Result<()> {
+        let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let ranges = Arc::new(std::sync::Mutex::new(vec![]));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let ranges = ranges.clone();
+                move |headers: HeaderMap| async move {
+                    ranges.lock().unwrap().push(headers.get(RANGE).cloned());
+                    ([(LAST_MODIFIED, last_modified)], "Hello, World!")
+                }
+            }),
+        );
+        let app = app_with_backend(backend).await?;
+        let get = |range: &'static str| {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(RANGE, HeaderValue::from_static(range))
+        };
+        // the full response is fetched and cached, the range is served from it.
+        let rep = get("bytes=0-4").await;
+        rep.assert_status(StatusCode::PARTIAL_CONTENT);
+        rep.assert_text("Hello");
+        assert_eq!(rep.headers().get("x-cache").unwrap(), "MISS");
+        let etag = rep.headers().get(ETAG).unwrap().clone();
+        sleep(Duration::from_millis(100)).await;
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, World!");
+        let rep = get("bytes=-6").await;
+        rep.assert_status(StatusCode::PARTIAL_CONTENT);
+        rep.assert_text("World!");
+        assert_eq!(rep.headers().get(CONTENT_RANGE).unwrap(), "bytes 7-12/13");
+        // a range ending before its start is invalid, it is ignored and the full entry is served.
+        let rep = get("bytes=5-3").await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, World!");
+        // a valid range starting after the end of the body can not be satisfied.
+        get("bytes=20-30")
+            .await
+            .assert_status(StatusCode::RANGE_NOT_SATISFIABLE);
+        // If-Range with the validator of the entry serves the range, the full entry otherwise.
+        for (if_range, status) in [
+            (etag.clone(), StatusCode::PARTIAL_CONTENT),
+            (
+                HeaderValue::from_static(last_modified),
+                StatusCode::PARTIAL_CONTENT,
+            ),
+            (HeaderValue::from_static("\"other\""), StatusCode::OK),
+            (
+                HeaderValue::from_str(&format!("W/\"{}\"", etag.to_str()?))?,
+                StatusCode::OK,
+            ),
+            (
+                HeaderValue::from_static("Thu, 22 Oct 2015 07:28:00 GMT"),
+                StatusCode::OK,
+            ),
+        ] {
+            get("bytes=0-4")
+                .add_header(HeaderName::from_static("if-range"), if_range)
+                .await
+                .assert_status(status);
+        }
+        // several ranges are passed through to the backend service.
+        let rep = get("bytes=0-1,3-4").await;
+        rep.assert_status_ok();
+        assert!(rep.headers().get("x-cache").is_none());
+        assert_eq!(
+            *ranges.lock().unwrap(),
+            [None, Some(HeaderValue::from_static("bytes=0-1,3-4"))]
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn backend_accept_ranges() -> Result<()> {
+        let backend = Router::new()
+            .route(
+                "/bytes",
+                get(|| async { ([(ACCEPT_RANGES, "bytes")], "Hello, World!") }),
+            )
+            .route(
+                "/none",
+                get(|| async { ([(ACCEPT_RANGES, "none")], "Hello, World!") }),
+            );
+        let app = app_with_backend(backend).await?;
+        let get = |path: &str| {
+            app.get(path)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(RANGE, HeaderValue::from_static("bytes=0-4"))
+        };
+        // a backend service advertising byte ranges gets them served from the cache
+        for _ in 0..2 {
+            let rep = get("/bytes").await;
+            rep.assert_status(StatusCode::PARTIAL_CONTENT);
+            rep.assert_text("Hello");
+            assert_eq!(rep.headers().get(ACCEPT_RANGES).unwrap(), "bytes");
+            sleep(Duration::from_millis(100)).await;
+        }
+        // ranges are not served for a backend service refusing them
+        for _ in 0..2 {
+            let rep = get("/none").await;
+            rep.assert_status_ok();
+            rep.assert_text("Hello, World!");
+            assert_eq!(rep.headers().get(ACCEPT_RANGES).unwrap(), "none");
+            sleep(Duration::from_millis(100)).await;
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn concurrent_purges() -> Result<()> {
+        let config = Config {
+            max_concurrent_purges: 2,
+            ..Config::builder().build()
+        };
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        // two purges are running, they hold every permit
+        let running = state.purge_permits.try_acquire_many(2)?;
+        let purge = || {
+            app.delete("/api/1/cache/path/0")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+        };
+        let responses = join_all((0..3).map(|_| purge().into_future())).await;
+        responses
+            .iter()
+            .for_each(|r| r.assert_status(StatusCode::SERVICE_UNAVAILABLE));
+        drop(running);
+        purge().await.assert_status_not_found();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn stalled_backend() -> Result<()> {
+        // backend sending the headers and a first chunk, then nothing
+        let backend = Router::new().route(
+            "/",
+            get(|| async {
+                let first = stream::iter([Ok::<_, std::io::Error>("Hello")]);
+                Body::from_stream(first.chain(stream::pending()))
+            }),
+        );
+        let url = spawn_backend(backend).await?;
+        let config = Config {
+            backend_idle_timeout_ms: 200,
+            ..Config::builder().endpoint("example.com", url).build()
+        };
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status(StatusCode::GATEWAY_TIMEOUT);
+        sleep(Duration::from_millis(100)).await;
+        state.cache.run_pending_tasks().await;
+        assert_eq!(state.cache.entry_count(), 0);
+        assert!(state.index_cache.is_empty());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn client_cache_control() -> Result<()> {
+        let backend = Router::new().route(
+            "/",
+            get(|| async { ([(CACHE_CONTROL, "max-age=60")], "Hello, World!") }),
+        );
+        let url = spawn_backend(backend).await?;
+        let mut endpoint = Endpoint::new("example.com", url);
+        endpoint.client_cache_control = Some("no-store".to_string());
+        let config = Config::builder().with_endpoint(endpoint).build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let mut etag = String::new();
+        // miss then hit
+        for _ in 0..2 {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            assert_eq!(rep.headers().get(CACHE_CONTROL).unwrap(), "no-store");
+            etag = rep.headers().get(ETAG).unwrap().to_str()?.to_string();
+            sleep(Duration::from_millis(100)).await;
+        }
+        // the entry is fresh for the max-age of the backend service
+        let metadata = app
+            .get(&format!("/api/1/cache/{etag}/metadata"))
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(metadata["freshness"], 60);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn export_import() -> Result<()> {
+        let app = app().await?;
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        let etag = rep.headers().get(ETAG).unwrap().clone();
+        sleep(Duration::from_millis(100)).await;
+        let archive = app.get("/api/1/cache/export").await.into_bytes();
+        // another instance, without backend service
+        let other = TestServer::new(app_main(
+            new_state(Config::builder().build()),
+            OpenApi::default(),
+        ))
+        .unwrap();
+        other
+            .post("/api/1/cache/import")
+            .bytes(archive)
+            .await
+            .assert_text("1");
+        let rep = other
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, World!");
+        assert_eq!(rep.headers().get(ETAG).unwrap(), etag);
+        other
+            .post("/api/1/cache/import")
+            .bytes("not an archive".into())
+            .await
+            .assert_status_bad_request();
+        // larger than the cache
+        let config = Config::builder()
+            .cache(CacheConfig {
+                size_limit: 1,
+                ..Default::default()
+            })
+            .build();
+        let small = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        small
+            .post("/api/1/cache/import")
+            .bytes(vec![0; 1024 * 1024 + 1].into())
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn persist_cache() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let backend = backend.route(
+            "/short",
+            get(|| async { ([(CACHE_CONTROL, "max-age=1")], "Hello, World!") }),
+        );
+        let url = spawn_backend(backend).await?;
+        let path = std::env::temp_dir().join(format!("mnemosyne-{}.cache", Uuid::new_v4()));
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                persist_path: Some(path.clone()),
+                ..Default::default()
+            })
+            .build();
+        let state = new_state(config.clone());
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        for uri in ["/", "/short"] {
+            app.get(uri)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_status_ok();
+        }
+        shutdown(&state).await;
+        // the temporary file is renamed once written.
+        assert!(!path.with_extension("cache.tmp").exists());
+        // the entry of /short is stale and cannot be revalidated once loaded.
+        sleep(Duration::from_millis(1100)).await;
+        let state = new_state(config);
+        assert_eq!(load_cache(&state).await?, 1);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, World!");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+    #[tokio::test]
+    async fn no_store() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let backend = backend
+            .route(
+                "/no-store",
+                get(|| async { ([(CACHE_CONTROL, "no-store")], "Hello, World!") }),
+            )
+            .route(
+                "/private",
+                get(|| async { ([(CACHE_CONTROL, "private, max-age=60")], "Hello, World!") }),
+            );
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        for path in ["/no-store", "/private"] {
+            let rep = app
+                .get(path)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            rep.assert_status_ok();
+            rep.assert_text("Hello, World!");
+            assert!(rep.headers().get(ETAG).is_none());
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert!(state.index_cache.is_empty());
+        state.cache.run_pending_tasks().await;
+        assert_eq!(state.cache.entry_count(), 0);
+        // other responses are still cached
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        let etag = rep.headers().get(ETAG).unwrap().to_str()?;
+        app.get(&format!("/api/1/cache/{etag}"))
+            .await
+            .assert_status_ok();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn cacheable_statuses() -> Result<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let requests = requests.clone();
+                move || async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    (StatusCode::SERVICE_UNAVAILABLE, "Unavailable")
+                }
+            }),
+        );
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        // the error is forwarded every time without being stored
+        for _ in 0..2 {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            rep.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+            rep.assert_text("Unavailable");
+            assert!(rep.headers().get(ETAG).is_none());
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        let stats = app.get("/api/1/cache").await.json::<serde_json::Value>();
+        assert_eq!(stats["entries"], 0);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn cache_name() -> Result<()> {
+        let app =
+            TestServer::new(app_main(new_state(Config::default()), OpenApi::default())).unwrap();
+        let stats = app.get("/api/1/cache").await.json::<serde_json::Value>();
+        assert_eq!(stats["name"], "mnemosyne");
+        let config = Config::builder()
+            .cache(CacheConfig {
+                name: "mnemosyne-eu".to_string(),
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let stats = app.get("/api/1/cache").await.json::<serde_json::Value>();
+        assert_eq!(stats["name"], "mnemosyne-eu");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn set_expiration() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let app = TestServer::new(app_main(
+            new_state(Config::builder().endpoint("example.com", url).build()),
+            OpenApi::default(),
+        ))
+        .unwrap();
+        let get = || {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+        };
+        get().await.assert_status_ok();
+        sleep(Duration::from_millis(100)).await;
+        for invalid in ["0", "soon"] {
+            app.post("/api/1/config/cache/expiration")
+                .text(invalid)
+                .await
+                .assert_status(StatusCode::BAD_REQUEST);
+        }
+        app.post("/api/1/config/cache/expiration")
+            .text("1")
+            .await
+            .assert_status_ok();
+        app.get("/api/1/config/cache/expiration")
+            .await
+            .assert_text("1");
+        // the entry already cached expires with the new value
+        sleep(Duration::from_millis(1200)).await;
+        get().await.assert_status_ok();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn warm_cache() -> Result<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = {
+            let requests = requests.clone();
+            move || async move {
+                requests.fetch_add(1, Ordering::SeqCst);
+                "Hello, World!"
+            }
+        };
+        let backend = Router::new()
+            .route("/a", get(counted.clone()))
+            .route("/b", get(counted));
+        let url = spawn_backend(backend).await?;
+        let app = TestServer::new(app_main(
+            new_state(Config::builder().endpoint("example.com", url).build()),
+            OpenApi::default(),
+        ))
+        .unwrap();
+        let summary = app
+            .post("/api/1/cache/warm")
+            .json(&serde_json::json!([
+                { "host": "example.com", "path": "/a" },
+                { "host": "example.com", "path": "/b?page=2" },
+                { "host": "example.com", "path": "/missing" },
+                { "host": "example.com", "path": "no slash" },
+            ]))
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(summary["succeeded"], 2);
+        let mut failed = summary["failed"].as_array().unwrap().clone();
+        failed.sort_by_key(|f| f["status"].as_u64());
+        assert_eq!(failed[0]["path"], "no slash");
+        assert_eq!(failed[0]["status"], 400);
+        assert_eq!(failed[1]["path"], "/missing");
+        assert_eq!(failed[1]["status"], 404);
+        sleep(Duration::from_millis(100)).await;
+        // the warmed requests are served from the cache
+        for path in ["/a", "/b?page=2"] {
+            let rep = app
+                .get(path)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            assert_eq!(rep.headers().get("x-cache").unwrap(), "HIT");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn persist_config() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("mnemosyne-{}.toml", Uuid::new_v4()));
+        let config = Config::builder()
+            .endpoint("example.com", Url::parse("http://127.0.0.1:9934")?)
+            .build();
+        let state = new_state(config).with_config_path(&path);
+        let app = TestServer::new(app_main(state, OpenApi::default())).unwrap();
+        app.put("/api/1/config/endpoint/other.com")
+            .text("http://127.0.0.1:9935")
+            .await
+            .assert_status_ok();
+        app.delete("/api/1/config/endpoint/example.com")
+            .await
+            .assert_status_ok();
+        // the changes are found in the configuration loaded again from the file
+        let config = confy::load_path::<Config>(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(config.endpoints.len(), 1);
+        assert_eq!(config.endpoints[0].host, "other.com");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn fallback_value() -> Result<()> {
+        let app = app().await?;
+        app.post("/api/1/config/fallback")
+            .text("http://127.0.0.1:9935/")
+            .await
+            .assert_status_ok();
+        let rep = app.get("/api/1/config/fallback").await;
+        rep.assert_status_ok();
+        rep.assert_text("http://127.0.0.1:9935/");
+        // an invalid url is refused and the fallback is kept
+        app.post("/api/1/config/fallback")
+            .text("not an url")
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        app.get("/api/1/config/fallback")
+            .await
+            .assert_text("http://127.0.0.1:9935/");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn refuse_invalid_backend_urls() -> Result<()> {
+        let app = app().await?;
+        // not http, or pointing to Mnemosyne listening on 127.0.0.1:9830
+        for url in ["ftp://127.0.0.1:9935/", "http://127.0.0.1:9830/"] {
+            app.post("/api/1/config/fallback")
+                .text(url)
+                .await
+                .assert_status(StatusCode::BAD_REQUEST);
+            app.put("/api/1/config/endpoint/example.org")
+                .text(url)
+                .await
+                .assert_status(StatusCode::BAD_REQUEST);
+            app.put("/api/1/config/endpoint/example.com")
+                .text(url)
+                .await
+                .assert_status(StatusCode::BAD_REQUEST);
+        }
+        // the configuration is left untouched
+        assert_ne!(
+            app.get("/api/1/config/fallback").await.text(),
+            "http://127.0.0.1:9830/"
+        );
+        let endpoints = app
+            .get("/api/1/config/endpoint")
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(endpoints.as_array().unwrap().len(), 1);
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_text("Hello, World!");
+        Ok(())
+    }
+    #[tokio::test]
+    async fn vary_mixed_case() -> Result<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new()
+            .route(
+                "/",
+                get({
+                    let requests = requests.clone();
+                    move |headers: HeaderMap| async move {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        let language = headers[ACCEPT_LANGUAGE].to_str().unwrap().to_string();
+                        ([("Vary", "ACCEPT-Language")], language)
+                    }
+                }),
+            )
+            .route("/any", get(|| async { ([("Vary", "*")], "Hello, World!") }));
+        let app = app_with_backend(backend).await?;
+        for language in ["fr", "en", "fr", "en"] {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(ACCEPT_LANGUAGE, HeaderValue::from_static(language))
+                .await;
+            rep.assert_text(language);
+            sleep(Duration::from_millis(100)).await;
+        }
+        // one response per language, then served from the cache
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // a response varying on anything is not cached
+        let rep = app
+            .get("/any")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        rep.assert_status_ok();
+        assert!(rep.headers().get(ETAG).is_none());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn multiple_vary_headers() -> Result<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new()
+            .route(
+                "/",
+                get({
+                    let requests = requests.clone();
+                    move |headers: HeaderMap| async move {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        let value = |name| headers[name].to_str().unwrap().to_string();
+                        let mut rep = format!("{}-{}", value("accept-language"), value("x-theme"))
+                            .into_response();
+                        let vary = rep.headers_mut();
+                        vary.append(VARY, HeaderValue::from_static("Accept-Language"));
+                        vary.append(VARY, HeaderValue::from_static("X-Theme"));
+                        rep
+                    }
+                }),
+            )
+            .route(
+                "/any",
+                get(|| async { ([(VARY, "Accept"), (VARY, "*")], "Hello, World!") }),
+            );
+        let app = app_with_backend(backend).await?;
+        for theme in ["dark", "light", "dark", "light"] {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(ACCEPT_LANGUAGE, HeaderValue::from_static("fr"))
+                .add_header(
+                    HeaderName::from_static("x-theme"),
+                    HeaderValue::from_static(theme),
+                )
+                .await;
+            rep.assert_text(format!("fr-{theme}"));
+            sleep(Duration::from_millis(100)).await;
+        }
+        // the fields of both Vary headers are part of the cache key
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // `*` in any Vary header prevents caching
+        let rep = app
+            .get("/any")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        assert!(rep.headers().get(ETAG).is_none());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn caching_disabled() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let config = Config {
+            caching_enabled: false,
+            ..Config::builder().endpoint("example.com", url).build()
+        };
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        for _ in 0..2 {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            rep.assert_status_ok();
+            assert!(rep.headers().get(ETAG).is_none());
+            assert!(rep.headers().get("x-cache").is_none());
+            sleep(Duration::from_millis(100)).await;
+        }
+        // both requests reached the backend service and nothing was cached
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(state.index_cache.is_empty());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn backend_etag() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let backend = backend.route(
+            "/tagged",
+            get({
+                let requests = requests.clone();
+                move || async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    ([(ETAG, "\"v1\"")], "Hello, World!")
+                }
+            }),
+        );
+        let app = app_with_backend(backend).await?;
+        // the etag of the backend service is kept and can be used to revalidate with Mnemosyne
+        let rep = app
+            .get("/tagged")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        assert_eq!(rep.headers()[ETAG], "\"v1\"");
+        sleep(Duration::from_millis(100)).await;
+        for etag in ["\"v1\"", "W/\"v1\""] {
+            app.get("/tagged")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(IF_NONE_MATCH, HeaderValue::from_static(etag))
+                .await
+                .assert_status(StatusCode::NOT_MODIFIED);
+        }
+        app.get("/tagged")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_NONE_MATCH, HeaderValue::from_static("\"v2\""))
+            .await
+            .assert_status_ok();
+        // without etag from the backend service, the uuid of the entry is given
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        let etag = rep.headers()[ETAG].to_str()?;
+        assert!(Uuid::parse_str(etag).is_ok());
+        sleep(Duration::from_millis(100)).await;
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_NONE_MATCH, HeaderValue::from_str(etag)?)
+            .await
+            .assert_status(StatusCode::NOT_MODIFIED);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn if_modified_since() -> Result<()> {
+        let now = SystemTime::now();
+        let last_modified = httpdate::fmt_http_date(now - Duration::from_secs(100));
+        let (backend, requests) = counting_backend();
+        let backend = backend.route(
+            "/dated",
+            get(move || async move { ([(LAST_MODIFIED, last_modified)], "Hello, World!") }),
+        );
+        let app = app_with_backend(backend).await?;
+        app.get("/dated")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        sleep(Duration::from_millis(100)).await;
+        // not modified since the date known by the client
+        let since = httpdate::fmt_http_date(now - Duration::from_secs(10));
+        let rep = app
+            .get("/dated")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_MODIFIED_SINCE, HeaderValue::from_str(&since)?)
+            .await;
+        rep.assert_status(StatusCode::NOT_MODIFIED);
+        assert!(rep.as_bytes().is_empty());
+        // modified after the date known by the client
+        let since = httpdate::fmt_http_date(now - Duration::from_secs(1000));
+        let rep = app
+            .get("/dated")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_MODIFIED_SINCE, HeaderValue::from_str(&since)?)
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text("Hello, World!");
+        // without Last-Modified, the entry is always served
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_MODIFIED_SINCE, HeaderValue::from_str(&since)?)
+            .await
+            .assert_status_ok();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn cache_metrics() -> Result<()> {
+        let app = app().await?;
+        for _ in 0..2 {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_status_ok();
+            sleep(Duration::from_millis(100)).await;
+        }
+        let rep = app.get("/metrics").await;
+        rep.assert_status_ok();
+        let metrics = rep.text();
+        assert!(metrics.contains("# TYPE mnemosyne_cache_hits_total counter\n"));
+        assert!(metrics.contains("mnemosyne_cache_hits_total 1\n"));
+        assert!(metrics.contains("mnemosyne_cache_misses_total 1\n"));
+        assert!(metrics.contains("mnemosyne_backend_requests_total 1\n"));
+        assert!(metrics.contains("mnemosyne_backend_errors_total 0\n"));
+        assert!(metrics.contains("mnemosyne_cache_served_bytes_total 13\n"));
+        assert!(metrics.contains("# HELP mnemosyne_cache_entries "));
+        assert!(metrics.contains("mnemosyne_cache_entries 1\n"));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn health() -> Result<()> {
+        let app = app().await?;
+        let rep = app.get("/health").await;
+        rep.assert_status_ok();
+        let health = rep.json::<serde_json::Value>();
+        assert_eq!(health["endpoints"], 1);
+        assert!(health["uptime_secs"].is_u64());
+        app.get("/ready").await.assert_status_ok();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn hit_ratio() -> Result<()> {
+        let app = app().await?;
+        let stats = app.get("/api/1/cache").await.json::<serde_json::Value>();
+        assert_eq!(stats["hit_ratio"], 0.0);
+        for _ in 0..2 {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await
+                .assert_status_ok();
+            sleep(Duration::from_millis(100)).await;
+        }
+        let stats = app.get("/api/1/cache").await.json::<serde_json::Value>();
+        assert_eq!(stats["hits"], 1);
+        assert_eq!(stats["misses"], 1);
+        assert_eq!(stats["hit_ratio"], 0.5);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn large_upload() -> Result<()> {
+        let backend = Router::new()
+            .route(
+                "/upload",
+                post(|body: axum::body::Bytes| async move { body.len().to_string() }),
+            )
+            .layer(axum::extract::DefaultBodyLimit::disable());
+        let app = app_with_backend(backend).await?;
+        let body = vec![b'a'; 8 * 1024 * 1024];
+        let rep = app
+            .post("/upload")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .bytes(body.into())
+            .await;
+        rep.assert_status_ok();
+        rep.assert_text((8 * 1024 * 1024).to_string());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn reload() -> Result<()> {
+        let first = spawn_backend(Router::new().route("/", get(|| async { "first" }))).await?;
+        let second = spawn_backend(Router::new().route("/new", get(|| async { "second" }))).await?;
+        let state = new_state(
+            Config::builder()
+                .endpoint("example.com", first.clone())
+                .build(),
+        );
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_text("first");
+        let config = Config::builder().endpoint("example.com", second).build();
+        reload_config(&state, config).await?;
+        // the next requests are sent to the new backend service
+        app.get("/new")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_text("second");
+        // a configuration looping on Mnemosyne is refused
+        let config = Config::builder()
+            .listen_address("127.0.0.1:9830".parse()?)
+            .fallback(Url::parse("http://127.0.0.1:9830")?)
+            .build();
+        assert!(reload_config(&state, config).await.is_err());
+        assert_eq!(state.config.read().await.endpoints.len(), 1);
+        // the maintenance mode enabled with the admin API survives a reload
+        app.post("/api/1/config/maintenance")
+            .text("true")
+            .await
+            .assert_status_ok();
+        let config = Config::builder().endpoint("example.com", first).build();
+        reload_config(&state, config).await?;
+        assert!(state.config.read().await.maintenance);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn endpoint_cache_override() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let url = spawn_backend(backend).await?;
+        let mut short = Endpoint::new("short.example.com", url.clone());
+        short.cache.default_ttl_secs = Some(1);
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .with_endpoint(short)
+            .cache(CacheConfig {
+                default_ttl_secs: 60,
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        for host in ["example.com", "short.example.com"] {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static(host))
+                .await
+                .assert_status_ok();
+        }
+        sleep(Duration::from_millis(1100)).await;
+        // the entry of the endpoint with a short ttl expired, the other one follows the global ttl
+        for host in ["example.com", "short.example.com"] {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static(host))
+                .await
+                .assert_status_ok();
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn tls_listener() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mnemosyne-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir)?;
+        let tls = TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        };
+        // a missing certificate is an error, not a panic
+        assert!(load_tls(&tls).await.is_err());
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        std::fs::write(&tls.cert_path, cert.cert.pem())?;
+        std::fs::write(&tls.key_path, cert.key_pair.serialize_pem())?;
+        let rustls = load_tls(&tls).await?;
+        std::fs::remove_dir_all(&dir)?;
+        let app = app_main(new_state(Config::builder().build()), OpenApi::default());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        spawn(
+            axum_server::from_tcp_rustls(listener, rustls)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>()),
+        );
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(cert.cert.pem().as_bytes())?)
+            .build()?;
+        let rep = client
+            .get(format!("https://localhost:{port}/health"))
+            .send()
+            .await?;
+        assert_eq!(rep.status(), StatusCode::OK);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn admin_token() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let config = Config {
+            // surrounding whitespace, as from a multiline string, is ignored
+            admin_token: Some("secret\n".to_string()),
+            ..Config::builder()
+                .endpoint("example.com", url.clone())
+                .build()
+        };
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let rep = app.get("/api/1/cache").await;
+        rep.assert_status(StatusCode::UNAUTHORIZED);
+        assert_eq!(rep.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+        for wrong in [
+            "Bearer wrong",
+            "Bearer ",
+            "Bearer secre",
+            "Bearer secrets",
+            "Basic secret",
+            "Bearersecret",
+        ] {
+            app.get("/api/1/cache")
+                .add_header(AUTHORIZATION, HeaderValue::from_static(wrong))
+                .await
+                .assert_status(StatusCode::UNAUTHORIZED);
+        }
+        app.get("/api/1/cache")
+            .add_header(AUTHORIZATION, HeaderValue::from_static("Bearer wrong"))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        // the scheme ignores case
+        for right in ["Bearer secret", "bearer secret", "BEARER  secret "] {
+            app.get("/api/1/cache")
+                .add_header(AUTHORIZATION, HeaderValue::from_static(right))
+                .await
+                .assert_status_ok();
+        }
+        // the proxy does not require the token
+        app.get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await
+            .assert_status_ok();
+        // without token configured, the API is open
+        let config = Config::builder().endpoint("example.com", url).build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        app.get("/api/1/cache").await.assert_status_ok();
+        Ok(())
+    }
+    #[tokio::test]
+    async fn concurrent_config_access() -> Result<()> {
+        let url = spawn_backend(router_backend()).await?;
+        let state = new_state(
+            Config::builder()
+                .endpoint("example.com", url.clone())
+                .build(),
+        );
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        // requests reading the configuration while the API modifies it.
+        let requests = (0..50).map(|i| {
+            app.get(&format!("/{i}"))
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .into_future()
+        });
+        let updates = (0..10).map(|i| {
+            app.put(&format!("/api/1/config/endpoint/example{i}.com"))
+                .text(url.to_string())
+                .into_future()
+        });
+        let (reps, updates) = tokio::time::timeout(
+            Duration::from_secs(10),
+            futures_util::future::join(join_all(requests), join_all(updates)),
+        )
+        .await?;
+        reps.iter().for_each(|rep| rep.assert_status_not_found());
+        updates.iter().for_each(|rep| rep.assert_status_ok());
+        assert_eq!(state.config.read().await.endpoints.len(), 11);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn coalesce_misses() -> Result<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let requests = requests.clone();
+                move || async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    sleep(Duration::from_millis(200)).await;
+                    "Hello, World!"
+                }
+            }),
+        );
+        let app = app_with_backend(backend).await?;
+        let reps = join_all((0..10).map(|_| {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .into_future()
+        }))
+        .await;
+        for rep in reps {
+            rep.assert_status_ok();
+            rep.assert_text("Hello, World!");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn coalesce_misses_per_variant() -> Result<()> {
+        let (requests, concurrent) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let max_concurrent = Arc::new(AtomicUsize::new(0));
+        let backend = Router::new().route(
+            "/",
+            get({
+                let (requests, concurrent, max_concurrent) =
+                    (requests.clone(), concurrent.clone(), max_concurrent.clone());
+                move || async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    let current = concurrent.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_concurrent.fetch_max(current, Ordering::SeqCst);
+                    sleep(Duration::from_millis(200)).await;
+                    concurrent.fetch_sub(1, Ordering::SeqCst);
+                    "Hello, World!"
+                }
+            }),
+        );
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                always_vary_on: vec!["Accept-Language".to_string()],
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let reps = join_all(["en", "fr", "en", "fr"].map(|language| {
+            app.get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(ACCEPT_LANGUAGE, HeaderValue::from_static(language))
+                .into_future()
+        }))
+        .await;
+        for rep in reps {
+            rep.assert_status_ok();
+        }
+        // one fetch per language, the languages do not wait for each other
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(max_concurrent.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn compressed_bodies() -> Result<()> {
+        let body = "Hello, World!".repeat(1000);
+        let backend = Router::new().route("/", get(move || async move { body }));
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder()
+            .endpoint("example.com", url)
+            .cache(CacheConfig {
+                compress_bodies: true,
+                ..Default::default()
+            })
+            .build();
+        let app = TestServer::new(app_main(new_state(config), OpenApi::default())).unwrap();
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        let fetched = rep.as_bytes().clone();
+        let etag = rep.headers().get(ETAG).unwrap().to_str()?.to_string();
+        sleep(Duration::from_millis(100)).await;
+        // the entry takes less space than the body
+        let stats = app.get("/api/1/cache").await.json::<serde_json::Value>();
+        assert!(stats["size"].as_u64().unwrap() < 13000);
+        let metadata = app
+            .get(&format!("/api/1/cache/{etag}/metadata"))
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(metadata["size"], 13000);
+        assert!(metadata["stored_size"].as_u64().unwrap() < 13000);
+        // and is served identical
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        assert!(rep.headers().get("x-cache-date").is_some());
+        assert_eq!(rep.as_bytes(), &fetched);
+        assert_eq!(fetched, "Hello, World!".repeat(1000).as_bytes());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn per_entry_ttl() -> Result<()> {
+        let backend = Router::new()
+            .route(
+                "/",
+                get(|| async { ([(CACHE_CONTROL, "max-age=1")], "Hello, World!") }),
+            )
+            .route(
+                "/shared",
+                get(|| async { ([(CACHE_CONTROL, "max-age=60, s-maxage=1")], "Hello, World!") }),
+            );
+        let url = spawn_backend(backend).await?;
+        let config = Config::builder().endpoint("example.com", url).build();
+        let state = new_state(config);
+        let app = TestServer::new(app_main(state.clone(), OpenApi::default())).unwrap();
+        let mut etags = vec![];
+        for path in ["/", "/shared"] {
+            let rep = app
+                .get(path)
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .await;
+            etags.push(rep.headers().get(ETAG).unwrap().to_str()?.to_string());
+        }
+        sleep(Duration::from_millis(100)).await;
+        for etag in &etags {
+            app.get(&format!("/api/1/cache/{etag}/metadata"))
+                .await
+                .assert_status_ok();
+        }
+        // the entries without validator are gone once their max-age or s-maxage elapsed
+        sleep(Duration::from_millis(1000)).await;
+        for etag in &etags {
+            app.get(&format!("/api/1/cache/{etag}/metadata"))
+                .await
+                .assert_status_not_found();
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn request_no_cache() -> Result<()> {
+        let (backend, requests) = counting_backend();
+        let app = app_with_backend(backend).await?;
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .await;
+        let etag = rep.headers().get(ETAG).unwrap().clone();
+        sleep(Duration::from_millis(100)).await;
+        // normal path, served from the cache
+        let rep = app
+            .get("/")
+            .add_header(HOST, HeaderValue::from_static("example.com"))
+            .add_header(IF_NONE_MATCH, etag.clone())
+            .await;
+        rep.assert_status(StatusCode::NOT_MODIFIED);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // no-cache path, the backend service is requested again, even with a valid etag
+        for (name, value) in [(CACHE_CONTROL, "no-cache"), (PRAGMA, "no-cache")] {
+            let rep = app
+                .get("/")
+                .add_header(HOST, HeaderValue::from_static("example.com"))
+                .add_header(IF_NONE_MATCH, etag.clone())
+                .add_header(name, HeaderValue::from_static(value))
+                .await;
+            rep.assert_status_ok();
+            rep.assert_text("Hello, World!");
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+}